@@ -0,0 +1,14 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = prost_build::Config::new();
+    config.bytes(&[".sentry_ext.InboundMessage.data"]);
+
+    tonic_build::configure()
+        .build_client(false)
+        .compile_with_config(
+            config,
+            &["protos/sentry_ext.proto", "protos/admin.proto"],
+            &["protos"],
+        )?;
+
+    Ok(())
+}
@@ -14,7 +14,12 @@ struct DummyServer;
 
 #[async_trait]
 impl CapabilityServer for DummyServer {
-    fn on_peer_connect(&self, _peer: PeerId, _: HashMap<CapabilityName, CapabilityVersion>) {
+    fn on_peer_connect(
+        &self,
+        _peer: PeerId,
+        _: HashMap<CapabilityName, CapabilityVersion>,
+        _: PeerConnectionInfo,
+    ) {
         info!("Peer connected")
     }
 
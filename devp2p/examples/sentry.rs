@@ -104,7 +104,12 @@ impl CapabilityServerImpl {
 #[async_trait]
 impl CapabilityServer for CapabilityServerImpl {
     #[instrument(skip(self, peer), fields(peer=&*peer.to_string()))]
-    fn on_peer_connect(&self, peer: PeerId, caps: HashMap<CapabilityName, CapabilityVersion>) {
+    fn on_peer_connect(
+        &self,
+        peer: PeerId,
+        caps: HashMap<CapabilityName, CapabilityVersion>,
+        _: PeerConnectionInfo,
+    ) {
         info!("Setting up peer state");
         let status_message = StatusMessage {
             protocol_version: *caps.get(&eth()).unwrap(),
@@ -262,6 +267,7 @@ async fn main() {
         .with_task_group(task_group.clone())
         .with_listen_options(ListenOptions {
             discovery_tasks,
+            priority_discovery_tasks: Default::default(),
             max_peers: 50,
             addr: format!("0.0.0.0:{}", port).parse().unwrap(),
             cidr: None,
@@ -3,7 +3,7 @@ use crate::types::*;
 use async_stream::stream;
 use futures::{stream::BoxStream, StreamExt};
 use std::{collections::HashMap, net::SocketAddr, pin::Pin, task::Poll, time::Duration};
-use tokio::time::sleep;
+use tokio::{sync::watch, time::sleep};
 use tokio_stream::Stream;
 
 #[cfg(feature = "discv4")]
@@ -18,7 +18,7 @@ pub use discv4;
 mod v5;
 
 #[cfg(feature = "discv5")]
-pub use self::v5::Discv5;
+pub use self::v5::{node_record_from_enr, Discv5};
 #[cfg(feature = "discv5")]
 pub use discv5;
 
@@ -36,9 +36,26 @@ pub struct StaticNodes(Pin<Box<dyn Stream<Item = anyhow::Result<NodeRecord>> + S
 
 impl StaticNodes {
     pub fn new(nodes: HashMap<SocketAddr, PeerId>, delay: Duration) -> Self {
+        Self::updatable(watch::channel(nodes).1, delay)
+    }
+
+    /// Nodes that can be replaced while the stream is running.
+    pub fn updatable(
+        mut nodes: watch::Receiver<HashMap<SocketAddr, PeerId>>,
+        delay: Duration,
+    ) -> Self {
         Self(Box::pin(stream! {
             loop {
-                for (&addr, &id) in &nodes {
+                let current = nodes.borrow_and_update().clone();
+                if current.is_empty() {
+                    if nodes.changed().await.is_err() {
+                        // No nodes, and there never will be.
+                        futures::future::pending::<()>().await;
+                    }
+                    continue;
+                }
+
+                for (addr, id) in current {
                     yield Ok(NodeRecord { id, addr });
                     sleep(delay).await;
                 }
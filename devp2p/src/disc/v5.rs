@@ -5,12 +5,23 @@ use async_stream::stream;
 use futures::stream::BoxStream;
 use futures_intrusive::channel::UnbufferedChannel;
 use secp256k1::PublicKey;
-use std::{pin::Pin, sync::Arc};
+use std::{net::SocketAddr, pin::Pin, sync::Arc};
 use task_group::TaskGroup;
 use tokio::{select, sync::mpsc::channel};
 use tokio_stream::Stream;
 use tracing::*;
 
+/// RLPx address and ID of the node, if its ENR has them.
+pub fn node_record_from_enr(enr: &discv5::Enr) -> Option<NodeRecord> {
+    let addr = SocketAddr::from((enr.ip()?, enr.tcp()?));
+    if let discv5::enr::CombinedPublicKey::Secp256k1(pk) = enr.public_key() {
+        let id = peer_id_from_pub_key(&PublicKey::from_slice(&pk.to_bytes()).ok()?);
+        Some(NodeRecord { addr, id })
+    } else {
+        None
+    }
+}
+
 pub struct Discv5 {
     #[allow(unused)]
     tasks: TaskGroup,
@@ -40,27 +51,9 @@ impl Discv5 {
                                 }
                             }
                             Ok(nodes) => {
-                                for node in nodes {
-                                    if let Some(ip) = node.ip() {
-                                        if let Some(port) = node.tcp() {
-                                            if let discv5::enr::CombinedPublicKey::Secp256k1(pk) =
-                                                node.public_key()
-                                            {
-                                                if tx
-                                                    .send(NodeRecord {
-                                                        addr: (ip, port).into(),
-                                                        id: peer_id_from_pub_key(
-                                                            &PublicKey::from_slice(&pk.to_bytes())
-                                                                .unwrap(),
-                                                        ),
-                                                    })
-                                                    .await
-                                                    .is_err()
-                                                {
-                                                    return;
-                                                }
-                                            }
-                                        }
+                                for record in nodes.iter().filter_map(node_record_from_enr) {
+                                    if tx.send(record).await.is_err() {
+                                        return;
                                     }
                                 }
                             }
@@ -8,6 +8,7 @@ use secp256k1::SecretKey;
 use std::{
     fmt::Debug,
     io,
+    net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
 };
@@ -236,6 +237,11 @@ where
     pub fn remote_id(&self) -> PeerId {
         self.remote_id
     }
+
+    /// Get the remote address
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.stream.get_ref().remote_addr()
+    }
 }
 
 impl<Io> Stream for ECIESStream<Io>
@@ -21,10 +21,11 @@ mod types;
 pub mod util;
 
 pub use disc::*;
+pub use node_filter::{BanTarget, MemoryNodeFilter, NodeFilter};
 pub use peer::{DisconnectReason, PeerStream};
 pub use peer_id::*;
 pub use rlpx::{ListenOptions, Swarm, SwarmBuilder};
 pub use types::{
     CapabilityId, CapabilityInfo, CapabilityName, CapabilityServer, CapabilityVersion,
-    InboundEvent, Message, NodeRecord, OutboundEvent,
+    ConnectionDirection, InboundEvent, Message, NodeRecord, OutboundEvent, PeerConnectionInfo,
 };
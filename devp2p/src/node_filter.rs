@@ -1,26 +1,50 @@
 use crate::peer_id::PeerId;
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fmt::Debug,
+    net::IpAddr,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     },
+    time::Instant,
 };
 
+/// Node or address a ban applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BanTarget {
+    Node(PeerId),
+    Ip(IpAddr),
+}
+
 pub trait NodeFilter: Debug + Send + 'static {
     fn max_peers(&self) -> usize;
     fn is_banned(&self, id: PeerId) -> bool;
-    fn is_allowed(&self, pool_size: usize, id: PeerId) -> bool {
-        pool_size < self.max_peers() && !self.is_banned(id)
+    fn is_ip_banned(&self, ip: IpAddr) -> bool;
+    /// Trusted nodes are admitted regardless of the peer limit, but not of bans.
+    fn is_trusted(&self, id: PeerId) -> bool;
+    fn is_allowed(&self, pool_size: usize, id: PeerId, ip: Option<IpAddr>) -> bool {
+        !self.is_banned(id)
+            && !ip.map_or(false, |ip| self.is_ip_banned(ip))
+            && (self.is_trusted(id) || pool_size < self.max_peers())
     }
-    fn ban(&mut self, id: PeerId);
+    /// Ban the node until the given moment, or forever if `None`.
+    fn ban(&mut self, id: PeerId, until: Option<Instant>);
+    fn unban(&mut self, id: PeerId);
+    /// Ban connections from and to the address until the given moment, or forever if `None`.
+    /// IPv4-mapped IPv6 addresses are the same as the IPv4 ones.
+    fn ban_ip(&mut self, ip: IpAddr, until: Option<Instant>);
+    fn unban_ip(&mut self, ip: IpAddr);
+    /// Bans in effect and when they expire.
+    fn bans(&self) -> Vec<(BanTarget, Option<Instant>)>;
+    fn set_trusted(&mut self, trusted: HashSet<PeerId>);
 }
 
 #[derive(Debug)]
 pub struct MemoryNodeFilter {
     peer_limiter: Arc<AtomicUsize>,
-    ban_list: HashSet<PeerId>,
+    ban_list: HashMap<BanTarget, Option<Instant>>,
+    trusted: HashSet<PeerId>,
 }
 
 impl MemoryNodeFilter {
@@ -28,8 +52,24 @@ impl MemoryNodeFilter {
         Self {
             peer_limiter,
             ban_list: Default::default(),
+            trusted: Default::default(),
+        }
+    }
+
+    fn is_target_banned(&self, target: BanTarget) -> bool {
+        match self.ban_list.get(&target) {
+            Some(Some(until)) => Instant::now() < *until,
+            Some(None) => true,
+            None => false,
         }
     }
+
+    fn ban_target(&mut self, target: BanTarget, until: Option<Instant>) {
+        let now = Instant::now();
+        self.ban_list
+            .retain(|_, until| until.map_or(true, |until| now < until));
+        self.ban_list.insert(target, until);
+    }
 }
 
 impl NodeFilter for MemoryNodeFilter {
@@ -38,10 +78,95 @@ impl NodeFilter for MemoryNodeFilter {
     }
 
     fn is_banned(&self, id: PeerId) -> bool {
-        self.ban_list.contains(&id)
+        self.is_target_banned(BanTarget::Node(id))
+    }
+
+    fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        self.is_target_banned(BanTarget::Ip(ip.to_canonical()))
+    }
+
+    fn is_trusted(&self, id: PeerId) -> bool {
+        self.trusted.contains(&id)
+    }
+
+    fn ban(&mut self, id: PeerId, until: Option<Instant>) {
+        self.ban_target(BanTarget::Node(id), until);
+    }
+
+    fn unban(&mut self, id: PeerId) {
+        self.ban_list.remove(&BanTarget::Node(id));
     }
 
-    fn ban(&mut self, id: PeerId) {
-        self.ban_list.insert(id);
+    fn ban_ip(&mut self, ip: IpAddr, until: Option<Instant>) {
+        self.ban_target(BanTarget::Ip(ip.to_canonical()), until);
+    }
+
+    fn unban_ip(&mut self, ip: IpAddr) {
+        self.ban_list.remove(&BanTarget::Ip(ip.to_canonical()));
+    }
+
+    fn bans(&self) -> Vec<(BanTarget, Option<Instant>)> {
+        self.ban_list
+            .iter()
+            .filter(|(&target, _)| self.is_target_banned(target))
+            .map(|(&target, &until)| (target, until))
+            .collect()
+    }
+
+    fn set_trusted(&mut self, trusted: HashSet<PeerId>) {
+        self.trusted = trusted;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::Ipv4Addr, time::Duration};
+
+    #[test]
+    fn bans() {
+        let mut filter = MemoryNodeFilter::new(Arc::new(AtomicUsize::new(10)));
+        let node = PeerId::repeat_byte(1);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        filter.ban(node, None);
+        filter.ban_ip(ip, Some(Instant::now() + Duration::from_secs(60)));
+        filter.ban_ip(
+            Ipv4Addr::new(10, 0, 0, 2).into(),
+            Some(Instant::now() - Duration::from_secs(1)),
+        );
+        assert!(filter.is_banned(node));
+        assert!(!filter.is_allowed(0, node, None));
+        assert!(!filter.is_allowed(0, PeerId::repeat_byte(2), Some(ip)));
+        assert!(filter.is_ip_banned(ip));
+        assert!(filter.is_ip_banned(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped().into()));
+        assert!(!filter.is_ip_banned(Ipv4Addr::new(10, 0, 0, 2).into()));
+        assert_eq!(filter.bans().len(), 2);
+
+        filter.unban(node);
+        filter.unban_ip(ip);
+        assert!(filter.is_allowed(0, node, Some(ip)));
+        assert!(!filter.is_ip_banned(ip));
+        assert!(filter.bans().is_empty());
+    }
+
+    #[test]
+    fn trusted() {
+        let mut filter = MemoryNodeFilter::new(Arc::new(AtomicUsize::new(1)));
+        let node = PeerId::repeat_byte(1);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        filter.ban(node, None);
+        filter.ban_ip(ip, None);
+        assert!(!filter.is_allowed(0, node, Some(ip)));
+
+        filter.set_trusted([node].into_iter().collect());
+        assert!(!filter.is_allowed(0, node, None));
+        assert!(!filter.is_allowed(0, PeerId::repeat_byte(2), Some(ip)));
+
+        // Only explicit bans keep trusted nodes out.
+        filter.unban(node);
+        filter.unban_ip(ip);
+        assert!(filter.is_allowed(5, node, Some(ip)));
+        assert!(!filter.is_allowed(5, PeerId::repeat_byte(2), None));
     }
 }
@@ -12,6 +12,7 @@ use secp256k1::{PublicKey, SecretKey, SECP256K1};
 use std::{
     fmt::Debug,
     io,
+    net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
 };
@@ -133,6 +134,7 @@ impl Default for Snappy {
 pub struct PeerStream<Io> {
     stream: ECIESStream<Io>,
     client_version: String,
+    remote_client_version: String,
     shared_capabilities: Vec<CapabilityInfo>,
     port: u16,
     id: PeerId,
@@ -152,6 +154,16 @@ where
         self.remote_id
     }
 
+    /// Remote address of this peer
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.stream.remote_addr()
+    }
+
+    /// Client version reported by this peer in its hello message
+    pub fn remote_client_version(&self) -> &str {
+        &self.remote_client_version
+    }
+
     /// Get all capabilities of this peer stream
     pub fn capabilities(&self) -> &[CapabilityInfo] {
         &self.shared_capabilities
@@ -310,6 +322,7 @@ where
             remote_id: transport.remote_id(),
             stream: transport,
             client_version: nonhello_client_version,
+            remote_client_version: val.client_version,
             port,
             id,
             shared_capabilities,
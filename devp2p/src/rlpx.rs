@@ -24,7 +24,7 @@ use std::{
         atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc, Weak,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 use task_group::TaskGroup;
 use tokio::{
@@ -32,6 +32,7 @@ use tokio::{
     sync::{
         mpsc::{channel, unbounded_channel},
         oneshot::{channel as oneshot, Sender as OneshotSender},
+        watch,
     },
     time::sleep,
 };
@@ -105,13 +106,21 @@ async fn handle_incoming<TS, C>(
     node_filter: Arc<Mutex<dyn NodeFilter>>,
     tcp_incoming: TS,
     handshake_data: PeerStreamHandshakeData<C>,
+    mut stopped: watch::Receiver<bool>,
 ) where
     TS: TcpServer,
     C: CapabilityServer,
 {
     let _: anyhow::Result<()> = async {
         loop {
-            match tcp_incoming.accept().await {
+            let accepted = tokio::select! {
+                accepted = tcp_incoming.accept() => accepted,
+                _ = stopped.changed() => {
+                    debug!("Stopped accepting connections");
+                    return Ok(());
+                }
+            };
+            match accepted {
                 Err(e) => {
                     bail!("failed to accept peer: {:?}, shutting down", e);
                 }
@@ -141,6 +150,7 @@ fn setup_peer_state<C, Io>(
     streams: Weak<Mutex<PeerStreams>>,
     capability_server: Arc<C>,
     remote_id: PeerId,
+    direction: ConnectionDirection,
     peer: PeerStream<Io>,
 ) -> ConnectedPeerState
 where
@@ -153,11 +163,16 @@ where
         .copied()
         .map(|cap_info| (cap_info.name, cap_info.version))
         .collect::<HashMap<_, _>>();
+    let connection_info = PeerConnectionInfo {
+        remote_addr: peer.remote_addr(),
+        direction,
+        client_version: peer.remote_client_version().to_string(),
+    };
     let (mut sink, mut stream) = futures::StreamExt::split(peer);
     let (peer_disconnect_tx, mut peer_disconnect_rx) = unbounded_channel();
     let tasks = TaskGroup::default();
 
-    capability_server.on_peer_connect(remote_id, capability_set);
+    capability_server.on_peer_connect(remote_id, capability_set, connection_info);
 
     let pinged = Arc::new(AtomicBool::default());
     let (pings_tx, mut pings) = channel(1);
@@ -370,6 +385,7 @@ async fn handle_incoming_request<C, Io>(
         capability_server,
         port,
     } = handshake_data;
+    let remote_ip = stream.remote_addr().map(|addr| addr.ip());
     // Do handshake and convert incoming connection into stream.
     let peer_res = tokio::time::timeout(
         Duration::from_secs(HANDSHAKE_TIMEOUT_SECS),
@@ -406,12 +422,16 @@ async fn handle_incoming_request<C, Io>(
                     );
                 }
                 Entry::Vacant(entry) => {
-                    if node_filter.lock().is_allowed(total_connections, remote_id) {
+                    if node_filter
+                        .lock()
+                        .is_allowed(total_connections, remote_id, remote_ip)
+                    {
                         debug!("New incoming peer connected: {}", remote_id);
                         entry.insert(PeerState::Connected(setup_peer_state(
                             Arc::downgrade(&streams),
                             capability_server,
                             remote_id,
+                            ConnectionDirection::Inbound,
                             peer,
                         )));
                     } else {
@@ -472,6 +492,11 @@ pub struct Swarm<C: CapabilityServer> {
     streams: Arc<Mutex<PeerStreams>>,
 
     currently_connecting: Arc<AtomicUsize>,
+    discovery_ended: AtomicBool,
+    /// Since when the dialer has been looking for peers without discovering any.
+    discovery_idle_since: Mutex<Option<Instant>>,
+    stop_sender: watch::Sender<bool>,
+    stopped: watch::Receiver<bool>,
 
     node_filter: Arc<Mutex<dyn NodeFilter>>,
 
@@ -490,6 +515,7 @@ pub struct Swarm<C: CapabilityServer> {
 pub struct SwarmBuilder {
     task_group: Option<Arc<TaskGroup>>,
     listen_options: Option<ListenOptions>,
+    node_filter: Option<Arc<Mutex<dyn NodeFilter>>>,
     client_version: String,
 }
 
@@ -504,6 +530,14 @@ impl SwarmBuilder {
         self
     }
 
+    /// Use the given node filter instead of the default in-memory one.
+    ///
+    /// `ListenOptions::max_peers` is ignored in this case, peer limit is up to the filter.
+    pub fn with_node_filter(mut self, node_filter: Arc<Mutex<dyn NodeFilter>>) -> Self {
+        self.node_filter = Some(node_filter);
+        self
+    }
+
     pub fn with_client_version(mut self, version: String) -> Self {
         self.client_version = version;
         self
@@ -523,6 +557,7 @@ impl SwarmBuilder {
             capability_mask.into(),
             capability_server,
             self.listen_options,
+            self.node_filter,
         )
         .await
     }
@@ -533,6 +568,9 @@ impl SwarmBuilder {
 pub struct ListenOptions {
     #[educe(Debug(ignore))]
     pub discovery_tasks: StreamMap<String, Discovery>,
+    /// Finite discoveries drained before `discovery_tasks` are consulted.
+    #[educe(Debug(ignore))]
+    pub priority_discovery_tasks: StreamMap<String, Discovery>,
     pub max_peers: usize,
     pub addr: SocketAddr,
     pub cidr: Option<IpCidr>,
@@ -544,6 +582,7 @@ impl Swarm<()> {
         SwarmBuilder {
             task_group: None,
             listen_options: None,
+            node_filter: None,
             client_version: format!("rust-devp2p/{}", env!("CARGO_PKG_VERSION")),
         }
     }
@@ -567,6 +606,7 @@ impl<C: CapabilityServer> Swarm<C> {
         capabilities: CapabilitySet,
         capability_server: Arc<C>,
         listen_options: Option<ListenOptions>,
+        node_filter: Option<Arc<Mutex<dyn NodeFilter>>>,
     ) -> anyhow::Result<Arc<Self>> {
         let tasks = task_group.unwrap_or_default();
 
@@ -575,13 +615,16 @@ impl<C: CapabilityServer> Swarm<C> {
             .map_or(0, |options| options.addr.port());
 
         let streams = Arc::new(Mutex::new(PeerStreams::default()));
-        let node_filter = Arc::new(Mutex::new(MemoryNodeFilter::new(Arc::new(
-            listen_options
-                .as_ref()
-                .map_or(usize::MAX.into(), |options| options.max_peers.into()),
-        ))));
+        let node_filter = node_filter.unwrap_or_else(|| {
+            Arc::new(Mutex::new(MemoryNodeFilter::new(Arc::new(
+                listen_options
+                    .as_ref()
+                    .map_or(usize::MAX.into(), |options| options.max_peers.into()),
+            ))))
+        });
 
         let capabilities = Arc::new(capabilities);
+        let (stop_sender, stopped) = watch::channel(false);
 
         if let Some(options) = &listen_options {
             let tcp_incoming = TcpListener::bind(options.addr)
@@ -603,6 +646,7 @@ impl<C: CapabilityServer> Swarm<C> {
                     node_filter.clone(),
                     TokioCidrListener::new(tcp_incoming, cidr),
                     handshake_data,
+                    stopped.clone(),
                 )
             });
         }
@@ -611,6 +655,10 @@ impl<C: CapabilityServer> Swarm<C> {
             tasks: tasks.clone(),
             streams,
             currently_connecting: Default::default(),
+            discovery_ended: AtomicBool::new(listen_options.is_none()),
+            discovery_idle_since: Default::default(),
+            stop_sender,
+            stopped,
             node_filter,
             capabilities,
             capability_server,
@@ -625,25 +673,42 @@ impl<C: CapabilityServer> Swarm<C> {
                 async move {
                     loop {
                         if let Some(server) = server.upgrade() {
+                            if server.is_stopped() {
+                                debug!("Swarm stopped, dialer quitting");
+                                return;
+                            }
+
                             let streams_len = server.streams.lock().mapping.len();
                             let max_peers = server.node_filter.lock().max_peers();
 
                             if !options.no_new_peers.load(Ordering::SeqCst) && streams_len < max_peers {
                                 trace!("Discovering peers as our peer count is too low: {} < {}", streams_len, max_peers);
+                                let prioritized = !options.priority_discovery_tasks.is_empty();
+                                let discovery_tasks = if prioritized {
+                                    &mut options.priority_discovery_tasks
+                                } else {
+                                    &mut options.discovery_tasks
+                                };
                                 match tokio::time::timeout(
                                     Duration::from_secs(DISCOVERY_TIMEOUT_SECS),
-                                    options.discovery_tasks.next(),
+                                    discovery_tasks.next(),
                                 )
                                 .await {
                                     Err(_) => {
                                         debug!("Failed to get new peer: timed out");
+                                        server.discovery_idle_since.lock().get_or_insert_with(Instant::now);
+                                    }
+                                    Ok(None) if prioritized => {
+                                        debug!("Priority discoveries ended");
                                     }
                                     Ok(None) => {
                                         debug!("Discoveries ended, dialer quitting");
+                                        server.discovery_ended.store(true, Ordering::SeqCst);
                                         return;
                                     }
                                     Ok(Some((disc_id, Ok(NodeRecord { addr, id: remote_id })))) => {
                                         debug!("Discovered peer: {:?} ({})", remote_id, disc_id);
+                                        *server.discovery_idle_since.lock() = None;
                                         tokio::select! {
                                             _ = server.add_peer_inner(addr, remote_id, true) => {},
                                             _ = sleep(Duration::from_secs(DISCOVERY_CONNECT_TIMEOUT_SECS)) => {
@@ -655,6 +720,7 @@ impl<C: CapabilityServer> Swarm<C> {
                                 }
                             } else {
                                 trace!("Skipping discovery as current number of peers is too high: {} >= {}", streams_len, max_peers);
+                                *server.discovery_idle_since.lock() = None;
                                 sleep(Duration::from_secs(2)).await;
                             }
                         } else {
@@ -696,6 +762,7 @@ impl<C: CapabilityServer> Swarm<C> {
         let (tx, rx) = tokio::sync::oneshot::channel();
         let connection_id = Uuid::new_v4();
         let currently_connecting = self.currently_connecting.clone();
+        let stopped = self.is_stopped();
 
         // Start reaper task that will terminate this connection if connection future gets dropped.
         tasks.spawn_with_name(format!("connection {} reaper", connection_id), {
@@ -726,6 +793,14 @@ impl<C: CapabilityServer> Swarm<C> {
 
             currently_connecting.fetch_add(1, Ordering::Relaxed);
 
+            if stopped {
+                trace!(
+                    "Not connecting to peer {} as the swarm is stopped",
+                    remote_id
+                );
+                return Ok(false);
+            }
+
             {
                 let mut streams = streams.lock();
                 let node_filter = node_filter.lock();
@@ -745,7 +820,9 @@ impl<C: CapabilityServer> Swarm<C> {
                         );
                     }
                     Entry::Vacant(vacant) => {
-                        if untrusted_peer && !node_filter.is_allowed(connection_num, remote_id) {
+                        if untrusted_peer
+                            && !node_filter.is_allowed(connection_num, remote_id, Some(addr.ip()))
+                        {
                             trace!("rejecting peer {}", remote_id);
                         } else {
                             debug!("connecting to peer {} at {}", remote_id, addr);
@@ -792,6 +869,7 @@ impl<C: CapabilityServer> Swarm<C> {
                                 Arc::downgrade(&streams),
                                 capability_server,
                                 remote_id,
+                                ConnectionDirection::Outbound,
                                 peer,
                             ));
 
@@ -816,6 +894,29 @@ impl<C: CapabilityServer> Swarm<C> {
     pub fn dialing(&self) -> usize {
         self.currently_connecting.load(Ordering::Relaxed)
     }
+
+    /// Returns `true` once the dialer has stopped because all discovery sources are exhausted
+    pub fn discovery_ended(&self) -> bool {
+        self.discovery_ended.load(Ordering::SeqCst)
+    }
+
+    /// How long the dialer has been looking for peers without discovering any, `None` if it is not idle.
+    pub fn discovery_idle_for(&self) -> Option<Duration> {
+        self.discovery_idle_since
+            .lock()
+            .map(|since| since.elapsed())
+    }
+
+    /// Stop dialing and accepting new peers. Connected peers are kept.
+    pub fn stop(&self) {
+        // Cannot fail as we hold a receiver.
+        let _ = self.stop_sender.send(true);
+    }
+
+    /// Returns `true` once `stop` has been called
+    pub fn is_stopped(&self) -> bool {
+        *self.stopped.borrow()
+    }
 }
 
 impl<C: CapabilityServer> Deref for Swarm<C> {
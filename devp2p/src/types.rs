@@ -117,11 +117,35 @@ pub enum OutboundEvent {
     },
 }
 
+/// Side that established the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionDirection {
+    /// Remote peer dialed us.
+    Inbound,
+    /// We dialed the remote peer.
+    Outbound,
+}
+
+/// Details of a freshly established peer connection.
+#[derive(Clone, Debug)]
+pub struct PeerConnectionInfo {
+    /// Remote socket address, if known.
+    pub remote_addr: Option<SocketAddr>,
+    pub direction: ConnectionDirection,
+    /// Client version reported by the peer in its hello message.
+    pub client_version: String,
+}
+
 #[async_trait]
 #[auto_impl(&, Box, Arc)]
 pub trait CapabilityServer: Send + Sync + 'static {
     /// Should be used to set up relevant state for the peer.
-    fn on_peer_connect(&self, peer: PeerId, caps: HashMap<CapabilityName, CapabilityVersion>);
+    fn on_peer_connect(
+        &self,
+        peer: PeerId,
+        caps: HashMap<CapabilityName, CapabilityVersion>,
+        info: PeerConnectionInfo,
+    );
     /// Called on the next event for peer.
     async fn on_peer_event(&self, peer: PeerId, event: InboundEvent);
     /// Get the next event for peer.
@@ -130,7 +154,13 @@ pub trait CapabilityServer: Send + Sync + 'static {
 
 #[async_trait]
 impl CapabilityServer for () {
-    fn on_peer_connect(&self, _: PeerId, _: HashMap<CapabilityName, CapabilityVersion>) {}
+    fn on_peer_connect(
+        &self,
+        _: PeerId,
+        _: HashMap<CapabilityName, CapabilityVersion>,
+        _: PeerConnectionInfo,
+    ) {
+    }
 
     async fn on_peer_event(&self, _: PeerId, _: InboundEvent) {}
 
@@ -844,4 +844,9 @@ impl Node {
     pub fn num_nodes(&self) -> usize {
         self.connected.lock().len()
     }
+
+    /// Our IP as configured or last reported by a peer's pong, if known.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        Some(self.node_endpoint.read().address).filter(|ip| !ip.is_unspecified())
+    }
 }
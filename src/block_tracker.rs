@@ -0,0 +1,306 @@
+use devp2p::PeerIdHash;
+use ethereum_types::{H256, U256};
+use hashlink::LruCache;
+use std::collections::{
+    btree_map::Entry, hash_map::Entry as HashMapEntry, BTreeMap, HashMap, HashSet,
+};
+
+/// Number of announced block hashes remembered for resolving peers' best hashes.
+const KNOWN_BLOCKS: usize = 1024;
+
+/// How far past our own best block an announced number may be.
+const MAX_BLOCKS_AHEAD: u64 = 1024;
+
+/// Latest block a peer told us about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerHead {
+    pub hash: H256,
+    pub total_difficulty: U256,
+}
+
+#[derive(Clone, Debug)]
+pub struct BlockTracker {
+    block_by_peer: HashMap<PeerIdHash, u64>,
+    peers_by_block: BTreeMap<u64, HashSet<PeerIdHash>>,
+    head_by_peer: HashMap<PeerIdHash, PeerHead>,
+    /// Peers whose head hash we do not know the number of yet.
+    peers_by_unresolved_head: HashMap<H256, HashSet<PeerIdHash>>,
+    /// Confirmed block numbers, trusted to resolve other peers' heads.
+    block_numbers: LruCache<H256, u64>,
+    /// Announced numbers and who announced them, confirmed once another peer agrees.
+    claimed_numbers: LruCache<H256, (u64, PeerIdHash)>,
+    /// Our best block as set by the core, bounding announced numbers. No announcements are taken before it is set.
+    own_block: Option<u64>,
+}
+
+impl Default for BlockTracker {
+    fn default() -> Self {
+        Self {
+            block_by_peer: Default::default(),
+            peers_by_block: Default::default(),
+            head_by_peer: Default::default(),
+            peers_by_unresolved_head: Default::default(),
+            block_numbers: LruCache::new(KNOWN_BLOCKS),
+            claimed_numbers: LruCache::new(KNOWN_BLOCKS),
+            own_block: None,
+        }
+    }
+}
+
+impl BlockTracker {
+    pub fn set_block_number(&mut self, peer: PeerIdHash, block: u64, force_create: bool) {
+        match self.block_by_peer.entry(peer) {
+            HashMapEntry::Vacant(e) => {
+                if force_create {
+                    e.insert(block);
+                } else {
+                    return;
+                }
+            }
+            HashMapEntry::Occupied(mut e) => {
+                let old_block = std::mem::replace(e.get_mut(), block);
+                if let Entry::Occupied(mut entry) = self.peers_by_block.entry(old_block) {
+                    entry.get_mut().remove(&peer);
+
+                    if entry.get().is_empty() {
+                        entry.remove();
+                    }
+                }
+            }
+        }
+
+        self.peers_by_block.entry(block).or_default().insert(peer);
+    }
+
+    /// Like `set_block_number`, but never moves the peer back.
+    fn raise_block_number(&mut self, peer: PeerIdHash, block: u64) {
+        if self
+            .block_by_peer
+            .get(&peer)
+            .map_or(false, |&current| current < block)
+        {
+            self.set_block_number(peer, block, false);
+        }
+    }
+
+    fn unresolve_head(&mut self, peer: PeerIdHash) {
+        if let Some(head) = self.head_by_peer.get(&peer) {
+            if let HashMapEntry::Occupied(mut entry) =
+                self.peers_by_unresolved_head.entry(head.hash)
+            {
+                entry.get_mut().remove(&peer);
+
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+        }
+    }
+
+    /// Record peer's best block from its status. Its number is filled in once some peer announces the block.
+    pub fn set_head(&mut self, peer: PeerIdHash, head: PeerHead) {
+        if !self.block_by_peer.contains_key(&peer) {
+            return;
+        }
+
+        self.unresolve_head(peer);
+        self.head_by_peer.insert(peer, head);
+        match self.block_numbers.get(&head.hash).copied() {
+            Some(number) => self.raise_block_number(peer, number),
+            None => {
+                self.peers_by_unresolved_head
+                    .entry(head.hash)
+                    .or_default()
+                    .insert(peer);
+            }
+        }
+    }
+
+    pub fn set_own_block(&mut self, number: u64) {
+        self.own_block = Some(number);
+    }
+
+    fn confirm_block_number(&mut self, hash: H256, number: u64) {
+        self.claimed_numbers.remove(&hash);
+        self.block_numbers.insert(hash, number);
+        if let Some(peers) = self.peers_by_unresolved_head.remove(&hash) {
+            for peer in peers {
+                self.raise_block_number(peer, number);
+            }
+        }
+    }
+
+    /// Record a block announced by the peer. Total difficulty comes with full block announcements only.
+    /// Announced numbers move the announcing peer alone, and resolve other peers' heads once a second
+    /// peer announces the same one. Numbers far past our own best block are ignored.
+    pub fn on_announcement(
+        &mut self,
+        peer: PeerIdHash,
+        hash: H256,
+        number: u64,
+        total_difficulty: Option<U256>,
+    ) {
+        if self
+            .own_block
+            .map_or(true, |own| number > own.saturating_add(MAX_BLOCKS_AHEAD))
+        {
+            return;
+        }
+
+        let confirmed = match self.claimed_numbers.get(&hash).copied() {
+            Some((claimed, claimant)) if claimed == number && claimant != peer => true,
+            _ => {
+                self.claimed_numbers.insert(hash, (number, peer));
+                false
+            }
+        };
+        if confirmed {
+            self.confirm_block_number(hash, number);
+        }
+
+        self.raise_block_number(peer, number);
+        if let Some(total_difficulty) = total_difficulty {
+            if self
+                .head_by_peer
+                .get(&peer)
+                .map_or(true, |head| head.total_difficulty < total_difficulty)
+            {
+                self.set_head(
+                    peer,
+                    PeerHead {
+                        hash,
+                        total_difficulty,
+                    },
+                );
+            }
+        }
+    }
+
+    pub fn remove_peer(&mut self, peer: PeerIdHash) {
+        self.unresolve_head(peer);
+        self.head_by_peer.remove(&peer);
+        if let Some(block) = self.block_by_peer.remove(&peer) {
+            if let Entry::Occupied(mut entry) = self.peers_by_block.entry(block) {
+                entry.get_mut().remove(&peer);
+
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+        }
+    }
+
+    pub fn block_number(&self, peer: PeerIdHash) -> Option<u64> {
+        self.block_by_peer.get(&peer).copied()
+    }
+
+    pub fn head(&self, peer: PeerIdHash) -> Option<PeerHead> {
+        self.head_by_peer.get(&peer).copied()
+    }
+
+    pub fn peers_with_min_block(&self, block: u64) -> HashSet<PeerIdHash> {
+        self.peers_by_block
+            .range(block..)
+            .flat_map(|(_, v)| v)
+            .copied()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker_with_peers(peers: &[PeerIdHash]) -> BlockTracker {
+        let mut tracker = BlockTracker::default();
+        tracker.set_own_block(100);
+        for &peer in peers {
+            tracker.set_block_number(peer, 0, true);
+        }
+        tracker
+    }
+
+    #[test]
+    fn resolves_heads_from_announcements() {
+        let (a, b, c) = (
+            PeerIdHash::repeat_byte(1),
+            PeerIdHash::repeat_byte(2),
+            PeerIdHash::repeat_byte(3),
+        );
+        let mut tracker = tracker_with_peers(&[a, b, c]);
+
+        let head = PeerHead {
+            hash: H256::repeat_byte(0xaa),
+            total_difficulty: 100.into(),
+        };
+        tracker.set_head(a, head);
+        assert_eq!(tracker.block_number(a), Some(0));
+
+        // An announcement moves the announcer only, until another peer confirms it.
+        tracker.on_announcement(b, head.hash, 10, None);
+        tracker.on_announcement(b, head.hash, 10, None);
+        assert_eq!(tracker.block_number(a), Some(0));
+        assert_eq!(tracker.block_number(b), Some(10));
+        tracker.on_announcement(c, head.hash, 10, None);
+        assert_eq!(tracker.block_number(a), Some(10));
+        assert_eq!(tracker.block_number(c), Some(10));
+        assert_eq!(tracker.head(b), None);
+
+        tracker.on_announcement(b, H256::repeat_byte(0xbb), 11, Some(110.into()));
+        assert_eq!(tracker.head(b).unwrap().total_difficulty, 110.into());
+        assert_eq!(tracker.peers_with_min_block(11), HashSet::from([b]));
+
+        // Stale announcements do not move peers back.
+        tracker.on_announcement(a, H256::repeat_byte(0xcc), 5, Some(50.into()));
+        assert_eq!(tracker.block_number(a), Some(10));
+        assert_eq!(tracker.head(a), Some(head));
+
+        tracker.remove_peer(a);
+        assert_eq!(tracker.head(a), None);
+        assert_eq!(tracker.block_number(a), None);
+    }
+
+    #[test]
+    fn forged_new_block() {
+        let (a, b) = (PeerIdHash::repeat_byte(1), PeerIdHash::repeat_byte(2));
+        let mut tracker = tracker_with_peers(&[a, b]);
+        let forged = H256::repeat_byte(0xaa);
+        tracker.set_head(
+            b,
+            PeerHead {
+                hash: forged,
+                total_difficulty: 100.into(),
+            },
+        );
+
+        // A single peer's block resolves no other heads.
+        tracker.on_announcement(a, forged, 1000, Some(200.into()));
+        assert_eq!(tracker.block_number(a), Some(1000));
+        assert_eq!(tracker.block_number(b), Some(0));
+
+        // Nor does it raise the bound.
+        tracker.on_announcement(a, H256::repeat_byte(0xbb), 2000, Some(300.into()));
+        assert_eq!(tracker.block_number(a), Some(1000));
+        assert_eq!(tracker.head(a).unwrap().total_difficulty, 200.into());
+    }
+
+    #[test]
+    fn far_ahead_announcements() {
+        let (a, b) = (PeerIdHash::repeat_byte(1), PeerIdHash::repeat_byte(2));
+        let mut tracker = BlockTracker::default();
+        tracker.set_block_number(a, 0, true);
+        tracker.set_block_number(b, 0, true);
+
+        // Nothing is taken before the core tells us our own head.
+        tracker.on_announcement(a, H256::repeat_byte(0xaa), 10, None);
+        assert_eq!(tracker.block_number(a), Some(0));
+
+        tracker.set_own_block(100);
+        let hash = H256::repeat_byte(0xbb);
+        tracker.on_announcement(a, hash, u64::MAX, None);
+        tracker.on_announcement(b, hash, u64::MAX, None);
+        assert_eq!(tracker.block_number(a), Some(0));
+        assert_eq!(tracker.block_number(b), Some(0));
+        assert!(tracker.peers_with_min_block(u64::MAX).is_empty());
+    }
+}
@@ -1,8 +1,15 @@
+use anyhow::{anyhow, bail, Context};
 use cidr::IpCidr;
+use clap::{App, ArgMatches, ArgSettings, FromArgMatches, IntoApp, Parser};
 use derive_more::FromStr;
-use devp2p::NodeRecord;
+use devp2p::{BanTarget, NodeRecord};
 use educe::Educe;
-use std::path::PathBuf;
+use std::{
+    ffi::OsString,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
 
 pub const BOOTNODES: &[&str] = &[
 	"enode://d860a01f9722d78051619d1e2351aba3f43f943f6f00718d1b9baa4101932a1f5011f16bb2b1bb35db20d6fe28fa0bf09636d26a87d31de9ec6203eeedb1f666@18.138.108.67:30303",   // bootnode-aws-ap-southeast-1-001
@@ -172,15 +179,45 @@ pub const BOOTNODES: &[&str] = &[
 )]
 #[educe(Debug)]
 pub struct Opts {
+    /// TOML file with settings named like the fields of this struct. Flags and environment
+    /// variables take precedence over it.
+    #[clap(long, env)]
+    pub config: Option<PathBuf>,
     #[clap(long, env)]
     #[educe(Debug(ignore))]
     pub node_key: Option<String>,
+    /// Node key file in geth's `nodekey` format, created on first run with `--node-key` or a new key.
+    /// Must not be world-readable.
+    #[clap(long, env)]
+    pub node_key_file: Option<PathBuf>,
+    /// geth `nodekey` file to replace the key in `--node-key-file` with.
+    #[clap(long, env)]
+    pub import_node_key: Option<PathBuf>,
+    /// New file to write the node key to in geth's `nodekey` format.
+    #[clap(long, env)]
+    pub export_node_key: Option<PathBuf>,
     #[clap(long, env, default_value = "30303")]
     pub listen_port: u16,
+    /// IP address other nodes reach us at, e.g. behind NAT. Learned by discv4 if unset.
+    #[clap(long, env)]
+    pub external_ip: Option<IpAddr>,
     #[clap(long, env)]
     pub cidr: Option<IpCidr>,
     #[clap(long, env, default_value = "127.0.0.1:8000")]
     pub sentry_addr: String,
+    /// PEM certificate to serve gRPC over TLS with.
+    #[clap(long, env)]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for `--tls-cert`.
+    #[clap(long, env)]
+    pub tls_key: Option<PathBuf>,
+    /// PEM CA certificate to verify gRPC client certificates against. Enables mutual TLS.
+    #[clap(long, env)]
+    pub tls_client_ca: Option<PathBuf>,
+    /// Require `authorization: Bearer <token>` metadata on gRPC requests.
+    #[clap(long, env)]
+    #[educe(Debug(ignore))]
+    pub auth_token: Option<String>,
     #[clap(long, env, default_value = "all.mainnet.ethdisco.net")]
     pub dnsdisc_address: String,
     #[clap(long, env, default_value = "30303")]
@@ -203,16 +240,143 @@ pub struct Opts {
     pub static_peers: Vec<NR>,
     #[clap(long, env, default_value = "5000")]
     pub static_peers_interval: u64,
+    /// Peers admitted regardless of `max_peers` and reputation, and redialed whenever disconnected.
+    /// Bans still apply to them.
+    #[clap(long, env)]
+    pub trusted_peers: Vec<NR>,
+    /// Node IDs and IP addresses banned permanently.
+    #[clap(long, env)]
+    pub bans: Vec<Ban>,
     #[clap(long, env, default_value = "8192")]
     pub max_peers: usize,
     #[clap(long, env, takes_value = false, /*, help = "Disable DNS, v4 & v5 discovery, only use static peers."*/)]
     pub no_discovery: bool,
     #[clap(long, env)]
     pub peers_file: Option<PathBuf>,
+    /// Number of latest inbound messages kept for subscribers to catch up or resume from.
+    #[clap(long, env, default_value = "8192")]
+    pub message_replay_buffer: usize,
+    /// Peer count below which the health service reports the sentry as not serving.
+    #[clap(long, env, default_value = "1")]
+    pub min_ready_peers: usize,
+    /// Requests of each type a peer may send per second.
+    #[clap(long, env, default_value = "20", parse(try_from_str = positive))]
+    pub request_rate: f64,
+    /// Requests of each type a peer may send at once.
+    #[clap(long, env, default_value = "100", parse(try_from_str = burst))]
+    pub request_burst: f64,
+    /// Announcements of each type a peer may send per second.
+    #[clap(long, env, default_value = "100", parse(try_from_str = positive))]
+    pub gossip_rate: f64,
+    /// Announcements of each type a peer may send at once.
+    #[clap(long, env, default_value = "500", parse(try_from_str = burst))]
+    pub gossip_burst: f64,
+    /// Outbound events queued per peer before gossip gets shed and other events wait.
+    #[clap(long, env, default_value = "256")]
+    pub outbound_queue_capacity: usize,
+    /// Address to serve the admin gRPC service at, which adds, removes and bans peers at runtime.
+    /// Disabled if not set. Must differ from `--sentry-addr`, so that cores cannot reach it.
+    #[clap(long, env)]
+    pub admin_addr: Option<SocketAddr>,
+    /// Require `authorization: Bearer <token>` metadata on admin gRPC requests.
+    #[clap(long, env)]
+    #[educe(Debug(ignore))]
+    pub admin_token: Option<String>,
+    /// Address to serve Prometheus metrics at. Disabled if not set.
+    #[clap(long, env)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Log filter in `RUST_LOG` syntax, overriding `RUST_LOG`.
+    #[clap(long, env)]
+    pub log_filter: Option<String>,
     #[clap(long, env, takes_value = false)]
     pub tokio_console: bool,
 }
 
+impl Opts {
+    /// Parse the command line and fill in settings it leaves unset from the config file.
+    /// Exits on invalid command line, like `Opts::parse`.
+    pub fn load() -> anyhow::Result<Self> {
+        let args = std::env::args_os().collect::<Vec<_>>();
+        let matches = Self::into_app().get_matches_from(&args);
+        Self::with_config_file(args, &matches)
+    }
+
+    /// Like `load`, but returns an error on invalid command line.
+    pub fn reload() -> anyhow::Result<Self> {
+        let args = std::env::args_os().collect::<Vec<_>>();
+        let matches = Self::into_app().try_get_matches_from(&args)?;
+        Self::with_config_file(args, &matches)
+    }
+
+    fn with_config_file(mut args: Vec<OsString>, matches: &ArgMatches) -> anyhow::Result<Self> {
+        let path = match matches.value_of_os("config") {
+            Some(path) => PathBuf::from(path),
+            None => return Ok(Self::from_arg_matches(matches)?),
+        };
+
+        let config = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let config_args = config_file_args(&Self::into_app(), matches, &config)
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        args.extend(config_args);
+
+        Self::try_parse_from(args)
+            .with_context(|| format!("Invalid config file {}", path.display()))
+    }
+}
+
+/// Turn settings from the config file into flags, skipping the ones set on the command line
+/// or in the environment.
+fn config_file_args(
+    app: &App,
+    matches: &ArgMatches,
+    config: &str,
+) -> anyhow::Result<Vec<OsString>> {
+    let config = config.parse::<toml::Value>()?;
+    let settings = config
+        .as_table()
+        .ok_or_else(|| anyhow!("expected a table of settings"))?;
+
+    let mut args = Vec::new();
+    for (name, value) in settings {
+        let arg = app
+            .get_arguments()
+            .find(|arg| arg.get_name() == name && arg.get_long().is_some() && name != "config")
+            .ok_or_else(|| anyhow!("unknown setting `{}`", name))?;
+
+        let from_env = arg
+            .get_env()
+            .map_or(false, |env| std::env::var_os(env).is_some());
+        if matches.occurrences_of(name.as_str()) > 0 || from_env {
+            continue;
+        }
+
+        let flag = format!("--{}", arg.get_long().unwrap());
+        let values = match value {
+            toml::Value::Array(values) => values.as_slice(),
+            value => std::slice::from_ref(value),
+        };
+        for value in values {
+            let value = match value {
+                toml::Value::Boolean(set) if !arg.is_set(ArgSettings::TakesValue) => {
+                    if *set {
+                        args.push(flag.clone().into());
+                    }
+                    continue;
+                }
+                toml::Value::String(value) => value.clone(),
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                _ => bail!("unsupported value for `{}`", name),
+            };
+            args.push(format!("{}={}", flag, value).into());
+        }
+    }
+
+    Ok(args)
+}
+
 #[derive(Debug, Educe)]
 #[educe(Default)]
 pub struct DnsDiscConfig {
@@ -225,3 +389,102 @@ pub struct NR(pub NodeRecord);
 
 #[derive(Debug, FromStr)]
 pub struct Discv4NR(pub discv4::NodeRecord);
+
+/// Node ID in hex or IP address.
+#[derive(Clone, Copy, Debug)]
+pub struct Ban(pub BanTarget);
+
+impl FromStr for Ban {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(ip) = s.parse() {
+            return Ok(Self(BanTarget::Ip(ip)));
+        }
+
+        s.parse()
+            .map(|id| Self(BanTarget::Node(id)))
+            .map_err(|_| format!("expected a node ID or an IP address, got {}", s))
+    }
+}
+
+/// Parses a rate limit, which must be finite and above zero so that it lets messages through.
+fn positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if value.is_finite() && value > 0.0 => Ok(value),
+        _ => Err(format!("expected a positive number, got {}", s)),
+    }
+}
+
+/// Parses a rate limit burst, which must fit at least one message.
+fn burst(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if value.is_finite() && value >= 1.0 => Ok(value),
+        _ => Err(format!("expected a number of at least 1, got {}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_file() {
+        let args = [
+            "ethereum-sentry",
+            "--max-peers",
+            "10",
+            "--config",
+            "sentry.toml",
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect::<Vec<_>>();
+        let matches = Opts::into_app().get_matches_from(&args);
+        let config = r#"
+            max_peers = 50
+            listen_port = 30304
+            discv5 = true
+            no_discovery = false
+            static_peers = [
+                "enode://d860a01f9722d78051619d1e2351aba3f43f943f6f00718d1b9baa4101932a1f5011f16bb2b1bb35db20d6fe28fa0bf09636d26a87d31de9ec6203eeedb1f666@18.138.108.67:30303",
+            ]
+            bans = ["10.0.0.1"]
+        "#;
+
+        let mut args = args;
+        args.extend(config_file_args(&Opts::into_app(), &matches, config).unwrap());
+        let opts = Opts::try_parse_from(args).unwrap();
+        assert_eq!(opts.max_peers, 10);
+        assert_eq!(opts.listen_port, 30304);
+        assert!(opts.discv5);
+        assert!(!opts.no_discovery);
+        assert_eq!(opts.static_peers.len(), 1);
+        assert!(matches!(opts.bans[..], [Ban(BanTarget::Ip(_))]));
+
+        assert!(config_file_args(&Opts::into_app(), &matches, "max_peer = 50").is_err());
+        assert!(config_file_args(&Opts::into_app(), &matches, "config = \"other.toml\"").is_err());
+    }
+
+    #[test]
+    fn rate_limits() {
+        let parse = |arg: &str| Opts::try_parse_from(["ethereum-sentry", arg]);
+
+        assert_eq!(parse("--request-rate=0.5").unwrap().request_rate, 0.5);
+        for arg in [
+            "--request-rate=0",
+            "--request-burst=-1",
+            "--request-burst=0.5",
+            "--gossip-rate=NaN",
+            "--gossip-burst=inf",
+        ] {
+            assert!(parse(arg).is_err(), "{}", arg);
+        }
+
+        let matches = Opts::into_app().get_matches_from(["ethereum-sentry"]);
+        let args = config_file_args(&Opts::into_app(), &matches, "gossip_rate = 0.0").unwrap();
+        assert!(
+            Opts::try_parse_from(std::iter::once("ethereum-sentry".into()).chain(args)).is_err()
+        );
+    }
+}
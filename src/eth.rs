@@ -1,10 +1,13 @@
 use anyhow::anyhow;
 use arrayvec::ArrayString;
+use bytes::Bytes;
 use devp2p::*;
 use enum_primitive_derive::*;
 use ethereum_forkid::{ForkFilter, ForkId};
 use ethereum_types::*;
+use rlp::{Decodable, DecoderError, Encodable, Rlp, RlpStream};
 use rlp_derive::*;
+use sha3::{Digest, Keccak256};
 use std::{collections::BTreeSet, convert::TryFrom};
 
 pub fn capability_name() -> CapabilityName {
@@ -32,6 +35,8 @@ pub struct StatusData {
     pub network_id: u64,
     pub total_difficulty: U256,
     pub best_hash: H256,
+    /// Number of the best block.
+    pub best_block: u64,
     pub fork_data: Forks,
 }
 
@@ -66,6 +71,7 @@ impl TryFrom<ethereum_interfaces::sentry::StatusData> for FullStatusData {
                 .ok_or_else(|| anyhow!("no total difficulty"))?
                 .into(),
             best_hash: best_hash.ok_or_else(|| anyhow!("no best hash"))?.into(),
+            best_block: max_block,
             fork_data: Forks {
                 genesis,
                 forks: fork_data.forks.into_iter().collect(),
@@ -79,7 +85,7 @@ impl TryFrom<ethereum_interfaces::sentry::StatusData> for FullStatusData {
     }
 }
 
-#[derive(Clone, Copy, Debug, Primitive)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Primitive)]
 pub enum EthMessageId {
     Status = 0,
     NewBlockHashes = 1,
@@ -98,8 +104,366 @@ pub enum EthMessageId {
     Receipts = 16,
 }
 
-#[derive(Clone, Copy, Debug, Primitive)]
+/// eth/68 `NewPooledTransactionHashes`: hashes along with transaction types and sizes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NewPooledTransactionHashes68 {
+    pub types: Vec<u8>,
+    pub sizes: Vec<usize>,
+    pub hashes: Vec<H256>,
+}
+
+impl Encodable for NewPooledTransactionHashes68 {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(3);
+        s.append(&self.types);
+        s.append_list(&self.sizes);
+        s.append_list(&self.hashes);
+    }
+}
+
+impl Decodable for NewPooledTransactionHashes68 {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        if rlp.item_count()? != 3 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+
+        let this = Self {
+            types: rlp.val_at(0)?,
+            sizes: rlp.list_at(1)?,
+            hashes: rlp.list_at(2)?,
+        };
+
+        if this.types.len() != this.hashes.len() || this.sizes.len() != this.hashes.len() {
+            return Err(DecoderError::Custom(
+                "types, sizes and hashes must have equal length",
+            ));
+        }
+
+        Ok(this)
+    }
+}
+
+/// `NewPooledTransactionHashes` payload in either of its layouts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PooledTransactionAnnouncement {
+    /// eth/65 - eth/67: plain list of hashes.
+    Hashes(Vec<H256>),
+    /// eth/68: hashes with types and sizes.
+    Typed(NewPooledTransactionHashes68),
+}
+
+impl PooledTransactionAnnouncement {
+    /// Decodes either layout. Only the eth/68 one has a nested list as its second item.
+    pub fn decode(data: &[u8]) -> Result<Self, DecoderError> {
+        let rlp = Rlp::new(data);
+        if rlp.item_count()? == 3 && rlp.at(1)?.is_list() {
+            Ok(Self::Typed(rlp.as_val()?))
+        } else {
+            Ok(Self::Hashes(rlp.as_list()?))
+        }
+    }
+
+    /// Decodes the layout used by peers speaking `version`.
+    pub fn decode_for(data: &[u8], version: EthProtocolVersion) -> Result<Self, DecoderError> {
+        if version >= EthProtocolVersion::Eth68 {
+            Ok(Self::Typed(rlp::decode(data)?))
+        } else {
+            Ok(Self::Hashes(Rlp::new(data).as_list()?))
+        }
+    }
+
+    pub fn hashes(&self) -> &[H256] {
+        match self {
+            Self::Hashes(hashes) => hashes,
+            Self::Typed(announcement) => &announcement.hashes,
+        }
+    }
+}
+
+/// Hash of a transaction as found in a `Transactions` or `PooledTransactions` list.
+/// Typed transactions are wrapped in a string, legacy ones are lists.
+pub fn transaction_hash(tx: &Rlp) -> Result<H256, DecoderError> {
+    let data = if tx.is_list() {
+        tx.as_raw()
+    } else {
+        tx.data()?
+    };
+    Ok(H256::from_slice(&Keccak256::digest(data)))
+}
+
+fn list_transaction_hashes(rlp: &Rlp) -> Result<Vec<H256>, DecoderError> {
+    if !rlp.is_list() {
+        return Err(DecoderError::RlpExpectedToBeList);
+    }
+
+    rlp.iter().map(|tx| transaction_hash(&tx)).collect()
+}
+
+/// Hashes of transactions in a `Transactions` message.
+pub fn transaction_hashes(data: &[u8]) -> Result<Vec<H256>, DecoderError> {
+    list_transaction_hashes(&Rlp::new(data))
+}
+
+/// Hashes of transactions in a `PooledTransactions` message, unwrapping the request ID envelope if the version has one.
+pub fn pooled_transaction_hashes(
+    data: &[u8],
+    version: EthProtocolVersion,
+) -> Result<Vec<H256>, DecoderError> {
+    let rlp = Rlp::new(data);
+    if version.has_request_ids() {
+        list_transaction_hashes(&rlp.at(1)?)
+    } else {
+        list_transaction_hashes(&rlp)
+    }
+}
+
+/// `NewBlockHashes` entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct BlockHashNumber {
+    pub hash: H256,
+    pub number: u64,
+}
+
+/// What `NewBlock` tells about the sender's head.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NewBlockAnnouncement {
+    pub hash: H256,
+    pub number: u64,
+    pub total_difficulty: U256,
+}
+
+impl NewBlockAnnouncement {
+    /// Decodes `[[header, transactions, ommers], total_difficulty]` without decoding the block body.
+    pub fn decode(data: &[u8]) -> Result<Self, DecoderError> {
+        let rlp = Rlp::new(data);
+        if rlp.item_count()? != 2 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+
+        let block = rlp.at(0)?;
+        if block.item_count()? != 3 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+
+        let header = block.at(0)?;
+        if header.item_count()? < 15 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+
+        Ok(Self {
+            hash: H256::from_slice(&Keccak256::digest(header.as_raw())),
+            number: header.val_at(8)?,
+            total_difficulty: rlp.val_at(1)?,
+        })
+    }
+}
+
+impl EthMessageId {
+    /// Whether this message exists in the given protocol version.
+    pub fn is_supported_by(self, version: EthProtocolVersion) -> bool {
+        match self {
+            Self::GetNodeData | Self::NodeData => version < EthProtocolVersion::Eth67,
+            _ => true,
+        }
+    }
+
+    /// Message the peer is supposed to answer this request with.
+    pub fn response_id(self) -> Option<Self> {
+        match self {
+            Self::GetBlockHeaders => Some(Self::BlockHeaders),
+            Self::GetBlockBodies => Some(Self::BlockBodies),
+            Self::GetPooledTransactions => Some(Self::PooledTransactions),
+            Self::GetNodeData => Some(Self::NodeData),
+            Self::GetReceipts => Some(Self::Receipts),
+            _ => None,
+        }
+    }
+
+    /// Announcements meant for everyone interested, as opposed to requests and responses.
+    pub fn is_gossip(self) -> bool {
+        matches!(
+            self,
+            Self::NewBlockHashes
+                | Self::Transactions
+                | Self::NewBlock
+                | Self::NewPooledTransactionHashes
+        )
+    }
+
+    pub fn is_response(self) -> bool {
+        matches!(
+            self,
+            Self::BlockHeaders
+                | Self::BlockBodies
+                | Self::PooledTransactions
+                | Self::NodeData
+                | Self::Receipts
+        )
+    }
+}
+
+/// Request ID of an eth/66 `[request-id, payload]` envelope.
+pub fn request_id(data: &[u8]) -> Result<u64, DecoderError> {
+    let rlp = Rlp::new(data);
+    if rlp.item_count()? != 2 {
+        return Err(DecoderError::RlpIncorrectListLen);
+    }
+
+    rlp.val_at(0)
+}
+
+/// The eth/66 envelope with its request ID replaced.
+pub fn with_request_id(data: &[u8], request_id: u64) -> Result<Bytes, DecoderError> {
+    let rlp = Rlp::new(data);
+    if rlp.item_count()? != 2 {
+        return Err(DecoderError::RlpIncorrectListLen);
+    }
+
+    let mut s = RlpStream::new_list(2);
+    s.append(&request_id).append_raw(rlp.at(1)?.as_raw(), 1);
+    Ok(s.out().freeze())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Primitive)]
 pub enum EthProtocolVersion {
     Eth65 = 65,
     Eth66 = 66,
+    Eth67 = 67,
+    Eth68 = 68,
+}
+
+impl EthProtocolVersion {
+    /// All versions served by the sentry.
+    pub const ALL: [Self; 4] = [Self::Eth65, Self::Eth66, Self::Eth67, Self::Eth68];
+
+    /// Number of message IDs reserved by this version.
+    pub const fn message_space(self) -> CapabilityLength {
+        17
+    }
+
+    /// Whether request and response messages are wrapped into eth/66 request ID envelope.
+    pub fn has_request_ids(self) -> bool {
+        self >= Self::Eth66
+    }
+
+    pub fn capability_id(self) -> CapabilityId {
+        CapabilityId {
+            name: capability_name(),
+            version: self as CapabilityVersion,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed_announcement() -> NewPooledTransactionHashes68 {
+        NewPooledTransactionHashes68 {
+            types: vec![0, 2, 3],
+            sizes: vec![120, 300, 131_200],
+            hashes: vec![
+                H256::repeat_byte(1),
+                H256::repeat_byte(2),
+                H256::repeat_byte(3),
+            ],
+        }
+    }
+
+    #[test]
+    fn pooled_transaction_announcement_layouts() {
+        let typed = typed_announcement();
+        let hashes = typed.hashes.clone();
+
+        assert_eq!(
+            PooledTransactionAnnouncement::decode(&rlp::encode(&typed)).unwrap(),
+            PooledTransactionAnnouncement::Typed(typed.clone())
+        );
+        assert_eq!(
+            PooledTransactionAnnouncement::decode(&rlp::encode_list::<H256, _>(&hashes)).unwrap(),
+            PooledTransactionAnnouncement::Hashes(hashes.clone())
+        );
+
+        let empty = NewPooledTransactionHashes68::default();
+        assert_eq!(
+            PooledTransactionAnnouncement::decode(&rlp::encode(&empty)).unwrap(),
+            PooledTransactionAnnouncement::Typed(empty)
+        );
+
+        assert!(PooledTransactionAnnouncement::decode_for(
+            &rlp::encode_list::<H256, _>(&hashes),
+            EthProtocolVersion::Eth68
+        )
+        .is_err());
+        assert!(PooledTransactionAnnouncement::decode_for(
+            &rlp::encode(&typed),
+            EthProtocolVersion::Eth66
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn typed_announcement_length_mismatch() {
+        let mut announcement = typed_announcement();
+        announcement.sizes.pop();
+
+        assert!(rlp::decode::<NewPooledTransactionHashes68>(&rlp::encode(&announcement)).is_err());
+    }
+
+    #[test]
+    fn transaction_hashes_of_both_kinds() {
+        let legacy = rlp::encode_list::<u64, _>(&[1, 2, 3]);
+        let typed = [2_u8, 0xc1, 0x01];
+
+        let mut transactions = RlpStream::new_list(2);
+        transactions.append_raw(&legacy, 1);
+        transactions.append(&typed.to_vec());
+
+        assert_eq!(
+            transaction_hashes(&transactions.out()).unwrap(),
+            vec![
+                H256::from_slice(&Keccak256::digest(&legacy)),
+                H256::from_slice(&Keccak256::digest(&typed)),
+            ]
+        );
+        assert!(transaction_hashes(&rlp::encode(&1_u64)).is_err());
+
+        let transactions = transactions.out();
+        let mut response = RlpStream::new_list(2);
+        response.append(&7_u64).append_raw(&transactions, 1);
+        assert_eq!(
+            pooled_transaction_hashes(&response.out(), EthProtocolVersion::Eth66).unwrap(),
+            transaction_hashes(&transactions).unwrap()
+        );
+        assert_eq!(
+            pooled_transaction_hashes(&transactions, EthProtocolVersion::Eth65).unwrap(),
+            transaction_hashes(&transactions).unwrap()
+        );
+    }
+
+    #[test]
+    fn new_block_announcement() {
+        let mut header = RlpStream::new_list(15);
+        for i in 0..15 {
+            header.append(&if i == 8 { 1234 } else { 0_u64 });
+        }
+        let header = header.out();
+
+        let mut block = RlpStream::new_list(2);
+        block.begin_list(3);
+        block.append_raw(&header, 1);
+        block.begin_list(0);
+        block.begin_list(0);
+        block.append(&U256::from(5678));
+
+        let announcement = NewBlockAnnouncement::decode(&block.out()).unwrap();
+        assert_eq!(
+            announcement.hash,
+            H256::from_slice(&Keccak256::digest(&header))
+        );
+        assert_eq!(announcement.number, 1234);
+        assert_eq!(announcement.total_difficulty, 5678.into());
+
+        assert!(NewBlockAnnouncement::decode(&rlp::encode_list::<u64, _>(&[1, 2])).is_err());
+    }
 }
@@ -1,51 +1,133 @@
-use crate::eth::{EthMessageId, EthProtocolVersion};
-use anyhow::bail;
+use crate::{
+    eth::{EthMessageId, EthProtocolVersion},
+    proto::sentry_ext::ExtMessageId,
+};
+use anyhow::{anyhow, bail};
 use ethereum_interfaces::sentry;
 use std::convert::TryFrom;
 
-impl From<EthMessageId> for sentry::MessageId {
-    fn from(id: EthMessageId) -> Self {
+/// Message ID in the sentry API for a message exchanged with a peer speaking `version`.
+/// eth/68 typed transaction announcements share the hash-only one here, see `ExtMessageId`.
+pub fn proto_message_id(id: EthMessageId, version: EthProtocolVersion) -> sentry::MessageId {
+    if version.has_request_ids() {
         match id {
-            EthMessageId::Status => Self::Status66,
-            EthMessageId::NewBlockHashes => Self::NewBlockHashes66,
-            EthMessageId::Transactions => Self::Transactions66,
-            EthMessageId::GetBlockHeaders => Self::GetBlockHeaders66,
-            EthMessageId::BlockHeaders => Self::BlockHeaders66,
-            EthMessageId::GetBlockBodies => Self::GetBlockBodies66,
-            EthMessageId::BlockBodies => Self::BlockBodies66,
-            EthMessageId::NewBlock => Self::NewBlock66,
-            EthMessageId::NewPooledTransactionHashes => Self::NewPooledTransactionHashes66,
-            EthMessageId::GetPooledTransactions => Self::GetPooledTransactions66,
-            EthMessageId::PooledTransactions => Self::PooledTransactions66,
-            EthMessageId::GetNodeData => Self::GetNodeData66,
-            EthMessageId::NodeData => Self::NodeData66,
-            EthMessageId::GetReceipts => Self::GetReceipts66,
-            EthMessageId::Receipts => Self::Receipts66,
+            EthMessageId::Status => sentry::MessageId::Status66,
+            EthMessageId::NewBlockHashes => sentry::MessageId::NewBlockHashes66,
+            EthMessageId::Transactions => sentry::MessageId::Transactions66,
+            EthMessageId::GetBlockHeaders => sentry::MessageId::GetBlockHeaders66,
+            EthMessageId::BlockHeaders => sentry::MessageId::BlockHeaders66,
+            EthMessageId::GetBlockBodies => sentry::MessageId::GetBlockBodies66,
+            EthMessageId::BlockBodies => sentry::MessageId::BlockBodies66,
+            EthMessageId::NewBlock => sentry::MessageId::NewBlock66,
+            EthMessageId::NewPooledTransactionHashes => {
+                sentry::MessageId::NewPooledTransactionHashes66
+            }
+            EthMessageId::GetPooledTransactions => sentry::MessageId::GetPooledTransactions66,
+            EthMessageId::PooledTransactions => sentry::MessageId::PooledTransactions66,
+            EthMessageId::GetNodeData => sentry::MessageId::GetNodeData66,
+            EthMessageId::NodeData => sentry::MessageId::NodeData66,
+            EthMessageId::GetReceipts => sentry::MessageId::GetReceipts66,
+            EthMessageId::Receipts => sentry::MessageId::Receipts66,
         }
+    } else {
+        match id {
+            EthMessageId::Status => sentry::MessageId::Status65,
+            EthMessageId::NewBlockHashes => sentry::MessageId::NewBlockHashes65,
+            EthMessageId::Transactions => sentry::MessageId::Transactions65,
+            EthMessageId::GetBlockHeaders => sentry::MessageId::GetBlockHeaders65,
+            EthMessageId::BlockHeaders => sentry::MessageId::BlockHeaders65,
+            EthMessageId::GetBlockBodies => sentry::MessageId::GetBlockBodies65,
+            EthMessageId::BlockBodies => sentry::MessageId::BlockBodies65,
+            EthMessageId::NewBlock => sentry::MessageId::NewBlock65,
+            EthMessageId::NewPooledTransactionHashes => {
+                sentry::MessageId::NewPooledTransactionHashes65
+            }
+            EthMessageId::GetPooledTransactions => sentry::MessageId::GetPooledTransactions65,
+            EthMessageId::PooledTransactions => sentry::MessageId::PooledTransactions65,
+            EthMessageId::GetNodeData => sentry::MessageId::GetNodeData65,
+            EthMessageId::NodeData => sentry::MessageId::NodeData65,
+            EthMessageId::GetReceipts => sentry::MessageId::GetReceipts65,
+            EthMessageId::Receipts => sentry::MessageId::Receipts65,
+        }
+    }
+}
+
+/// Outbound message ID from the sentry API along with its encoding.
+#[derive(Clone, Copy, Debug)]
+pub struct OutboundMessageId {
+    pub id: EthMessageId,
+    /// Whether the payload is wrapped into eth/66 request ID envelope.
+    pub request_ids: bool,
+}
+
+impl OutboundMessageId {
+    /// Whether a peer speaking `version` can receive this message.
+    pub fn is_supported_by(&self, version: EthProtocolVersion) -> bool {
+        self.request_ids == version.has_request_ids() && self.id.is_supported_by(version)
     }
 }
 
-impl TryFrom<sentry::MessageId> for EthMessageId {
+impl OutboundMessageId {
+    /// Parses a `sentry.MessageId` or `ExtMessageId` value.
+    pub fn from_proto(id: i32) -> anyhow::Result<Self> {
+        if id == ExtMessageId::NewPooledTransactionHashes68 as i32 {
+            return Ok(Self {
+                id: EthMessageId::NewPooledTransactionHashes,
+                request_ids: true,
+            });
+        }
+
+        sentry::MessageId::from_i32(id)
+            .ok_or_else(|| anyhow!("unrecognized message id {}", id))?
+            .try_into()
+    }
+}
+
+impl TryFrom<sentry::MessageId> for OutboundMessageId {
     type Error = anyhow::Error;
 
     fn try_from(id: sentry::MessageId) -> Result<Self, Self::Error> {
-        Ok(match id {
-            sentry::MessageId::NewBlockHashes66 => Self::NewBlockHashes,
-            sentry::MessageId::NewBlock66 => Self::NewBlock,
-            sentry::MessageId::Transactions66 => Self::Transactions,
-            sentry::MessageId::NewPooledTransactionHashes66 => Self::NewPooledTransactionHashes,
-            sentry::MessageId::GetBlockHeaders66 => Self::GetBlockHeaders,
-            sentry::MessageId::GetBlockBodies66 => Self::GetBlockBodies,
-            sentry::MessageId::GetNodeData66 => Self::GetNodeData,
-            sentry::MessageId::GetReceipts66 => Self::GetReceipts,
-            sentry::MessageId::GetPooledTransactions66 => Self::GetPooledTransactions,
-            sentry::MessageId::BlockHeaders66 => Self::BlockHeaders,
-            sentry::MessageId::BlockBodies66 => Self::BlockBodies,
-            sentry::MessageId::NodeData66 => Self::NodeData,
-            sentry::MessageId::Receipts66 => Self::Receipts,
-            sentry::MessageId::PooledTransactions66 => Self::PooledTransactions,
+        let (id, request_ids) = match id {
+            sentry::MessageId::NewBlockHashes66 => (EthMessageId::NewBlockHashes, true),
+            sentry::MessageId::NewBlock66 => (EthMessageId::NewBlock, true),
+            sentry::MessageId::Transactions66 => (EthMessageId::Transactions, true),
+            sentry::MessageId::NewPooledTransactionHashes66 => {
+                (EthMessageId::NewPooledTransactionHashes, true)
+            }
+            sentry::MessageId::GetBlockHeaders66 => (EthMessageId::GetBlockHeaders, true),
+            sentry::MessageId::GetBlockBodies66 => (EthMessageId::GetBlockBodies, true),
+            sentry::MessageId::GetNodeData66 => (EthMessageId::GetNodeData, true),
+            sentry::MessageId::GetReceipts66 => (EthMessageId::GetReceipts, true),
+            sentry::MessageId::GetPooledTransactions66 => {
+                (EthMessageId::GetPooledTransactions, true)
+            }
+            sentry::MessageId::BlockHeaders66 => (EthMessageId::BlockHeaders, true),
+            sentry::MessageId::BlockBodies66 => (EthMessageId::BlockBodies, true),
+            sentry::MessageId::NodeData66 => (EthMessageId::NodeData, true),
+            sentry::MessageId::Receipts66 => (EthMessageId::Receipts, true),
+            sentry::MessageId::PooledTransactions66 => (EthMessageId::PooledTransactions, true),
+            sentry::MessageId::NewBlockHashes65 => (EthMessageId::NewBlockHashes, false),
+            sentry::MessageId::NewBlock65 => (EthMessageId::NewBlock, false),
+            sentry::MessageId::Transactions65 => (EthMessageId::Transactions, false),
+            sentry::MessageId::NewPooledTransactionHashes65 => {
+                (EthMessageId::NewPooledTransactionHashes, false)
+            }
+            sentry::MessageId::GetBlockHeaders65 => (EthMessageId::GetBlockHeaders, false),
+            sentry::MessageId::GetBlockBodies65 => (EthMessageId::GetBlockBodies, false),
+            sentry::MessageId::GetNodeData65 => (EthMessageId::GetNodeData, false),
+            sentry::MessageId::GetReceipts65 => (EthMessageId::GetReceipts, false),
+            sentry::MessageId::GetPooledTransactions65 => {
+                (EthMessageId::GetPooledTransactions, false)
+            }
+            sentry::MessageId::BlockHeaders65 => (EthMessageId::BlockHeaders, false),
+            sentry::MessageId::BlockBodies65 => (EthMessageId::BlockBodies, false),
+            sentry::MessageId::NodeData65 => (EthMessageId::NodeData, false),
+            sentry::MessageId::Receipts65 => (EthMessageId::Receipts, false),
+            sentry::MessageId::PooledTransactions65 => (EthMessageId::PooledTransactions, false),
             other => bail!("Unsupported message id: {:?}", other),
-        })
+        };
+
+        Ok(Self { id, request_ids })
     }
 }
 
@@ -53,7 +135,10 @@ impl From<EthProtocolVersion> for sentry::Protocol {
     fn from(version: EthProtocolVersion) -> Self {
         match version {
             EthProtocolVersion::Eth65 => Self::Eth65,
-            EthProtocolVersion::Eth66 => Self::Eth66,
+            // eth/67 and eth/68 keep the eth/66 message encoding.
+            EthProtocolVersion::Eth66 | EthProtocolVersion::Eth67 | EthProtocolVersion::Eth68 => {
+                Self::Eth66
+            }
         }
     }
 }
@@ -0,0 +1,113 @@
+use crate::CapabilityServerImpl;
+use devp2p::Swarm;
+use std::{fmt, sync::Arc, time::Duration};
+use tokio::time::sleep;
+use tonic_health::{server::HealthReporter, ServingStatus};
+use tracing::*;
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long discovery may go without finding a peer, while we need more, before it is considered dead.
+const MAX_DISCOVERY_IDLE: Duration = Duration::from_secs(300);
+
+/// Why the sentry cannot serve cores yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotReady {
+    NoStatus,
+    TooFewPeers { peers: usize, min_peers: usize },
+    DiscoveryDead,
+}
+
+impl fmt::Display for NotReady {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoStatus => write!(f, "no status set by core"),
+            Self::TooFewPeers { peers, min_peers } => {
+                write!(f, "{} peers, need at least {}", peers, min_peers)
+            }
+            Self::DiscoveryDead => write!(f, "discovery is not finding peers"),
+        }
+    }
+}
+
+pub fn readiness(
+    has_status: bool,
+    peers: usize,
+    min_peers: usize,
+    discovery_dead: bool,
+) -> Result<(), NotReady> {
+    if !has_status {
+        return Err(NotReady::NoStatus);
+    }
+    if discovery_dead {
+        return Err(NotReady::DiscoveryDead);
+    }
+    if peers < min_peers {
+        return Err(NotReady::TooFewPeers { peers, min_peers });
+    }
+
+    Ok(())
+}
+
+/// Keeps serving status of `services` in line with sentry readiness.
+///
+/// The overall server status (empty service name) stays `SERVING` for as long as the process runs and is meant for liveness probes.
+pub async fn report_readiness(
+    mut reporter: HealthReporter,
+    swarm: Arc<Swarm<CapabilityServerImpl>>,
+    services: Vec<&'static str>,
+    min_peers: usize,
+    discovery_expected: bool,
+) {
+    let mut last = None;
+    loop {
+        let state = readiness(
+            swarm.has_status(),
+            swarm.connected_peers(),
+            min_peers,
+            discovery_expected
+                && (swarm.discovery_ended()
+                    || swarm
+                        .discovery_idle_for()
+                        .map_or(false, |idle| idle >= MAX_DISCOVERY_IDLE)),
+        );
+
+        if last != Some(state) {
+            let status = match state {
+                Ok(()) => {
+                    info!("Sentry is ready");
+                    ServingStatus::Serving
+                }
+                Err(reason) => {
+                    info!("Sentry is not ready: {}", reason);
+                    ServingStatus::NotServing
+                }
+            };
+            for &service in &services {
+                reporter.set_service_status(service, status).await;
+            }
+            last = Some(state);
+        }
+
+        sleep(HEALTH_CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readiness_conditions() {
+        assert_eq!(readiness(false, 10, 1, false), Err(NotReady::NoStatus));
+        assert_eq!(
+            readiness(true, 0, 1, false),
+            Err(NotReady::TooFewPeers {
+                peers: 0,
+                min_peers: 1
+            })
+        );
+        assert_eq!(readiness(true, 10, 1, true), Err(NotReady::DiscoveryDead));
+        assert_eq!(readiness(true, 1, 1, false), Ok(()));
+        assert_eq!(readiness(true, 0, 0, false), Ok(()));
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::Context;
+use devp2p::{NodeRecord, PeerId};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::*;
+
+/// Peers not seen for this long are not worth redialing.
+const MAX_PEER_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const MAX_KNOWN_PEERS: usize = 1024;
+
+#[derive(Clone, Copy, Debug)]
+struct KnownPeer {
+    addr: SocketAddr,
+    last_seen: SystemTime,
+}
+
+/// Peers that passed the Status handshake, persisted across restarts.
+///
+/// Stored one per line as `<enode> <last seen unix timestamp>`.
+#[derive(Clone, Debug, Default)]
+pub struct KnownPeers {
+    peers: HashMap<PeerId, KnownPeer>,
+}
+
+impl KnownPeers {
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = match tokio::fs::read_to_string(path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+
+        let now = SystemTime::now();
+        let mut peers = HashMap::new();
+        for line in data.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            match parse_line(line) {
+                Ok((NodeRecord { id, addr }, last_seen)) => {
+                    if now.duration_since(last_seen).unwrap_or_default() < MAX_PEER_AGE {
+                        peers.insert(id, KnownPeer { addr, last_seen });
+                    }
+                }
+                Err(e) => warn!("Skipping malformed peers file entry {:?}: {}", line, e),
+            }
+        }
+
+        Ok(Self { peers })
+    }
+
+    pub async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut peers = self.peers.iter().collect::<Vec<_>>();
+        peers.sort_by_key(|(_, peer)| std::cmp::Reverse(peer.last_seen));
+
+        let mut data = String::new();
+        for (id, peer) in peers.into_iter().take(MAX_KNOWN_PEERS) {
+            data.push_str(&format!(
+                "enode://{}@{} {}\n",
+                hex::encode(id.as_bytes()),
+                peer.addr,
+                peer.last_seen
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            ));
+        }
+
+        // Write to a temporary file first so that a crash never leaves a truncated file behind.
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, data)
+            .await
+            .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+        tokio::fs::rename(&tmp_path, path)
+            .await
+            .with_context(|| format!("failed to replace {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// Known peers, most recently seen first.
+    pub fn node_records(&self) -> Vec<NodeRecord> {
+        let mut peers = self.peers.iter().collect::<Vec<_>>();
+        peers.sort_by_key(|(_, peer)| std::cmp::Reverse(peer.last_seen));
+        peers
+            .into_iter()
+            .map(|(&id, peer)| NodeRecord {
+                id,
+                addr: peer.addr,
+            })
+            .collect()
+    }
+
+    pub fn seen(&mut self, id: PeerId, addr: SocketAddr) {
+        self.peers.insert(
+            id,
+            KnownPeer {
+                addr,
+                last_seen: SystemTime::now(),
+            },
+        );
+    }
+
+    pub fn forget(&mut self, id: PeerId) {
+        self.peers.remove(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+fn parse_line(line: &str) -> anyhow::Result<(NodeRecord, SystemTime)> {
+    let (record, last_seen) = line
+        .split_once(' ')
+        .ok_or_else(|| anyhow::anyhow!("no timestamp"))?;
+    let record = record
+        .parse::<NodeRecord>()
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    let last_seen = UNIX_EPOCH + Duration::from_secs(last_seen.trim().parse()?);
+
+    Ok((record, last_seen))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_entry() {
+        let id = PeerId::repeat_byte(1);
+        let (record, last_seen) = parse_line(&format!(
+            "enode://{}@10.0.0.1:30303 1600000000",
+            hex::encode(id.as_bytes())
+        ))
+        .unwrap();
+
+        assert_eq!(record.id, id);
+        assert_eq!(record.addr, "10.0.0.1:30303".parse().unwrap());
+        assert_eq!(last_seen, UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+
+        assert!(parse_line("enode://00@10.0.0.1:30303").is_err());
+    }
+}
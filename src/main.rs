@@ -1,112 +1,173 @@
 #![feature(let_chains)]
 #![allow(dead_code, clippy::upper_case_acronyms, incomplete_features)]
 
-use crate::{config::*, eth::*, services::*};
-use anyhow::{anyhow, Context};
-use async_stream::stream;
+use crate::{
+    block_tracker::*, config::*, eth::*, grpc::*, health::*, known_peers::*, message_log::*,
+    metrics::*, node_key::*, outbound::*, proto::admin::admin_server::AdminServer,
+    proto::sentry_ext::sentry_ext_server::SentryExtServer, proto::sentry_ext::ExtMessageId,
+    rate_limit::*, reload::*, reputation::*, requests::*, security::*, services::*, shutdown::*,
+    trusted_peers::*, types::*, validation::*,
+};
+use anyhow::{anyhow, bail, Context};
 use async_trait::async_trait;
-use clap::Parser;
 use devp2p::{PeerId, PeerIdHash, *};
 use educe::Educe;
-use ethereum_interfaces::sentry::{self, sentry_server::SentryServer, InboundMessage, PeersReply};
-use futures::stream::BoxStream;
-use maplit::btreemap;
+use ethereum_interfaces::sentry::{
+    self, peers_reply::PeerEvent, sentry_server::SentryServer, InboundMessage, PeersReply,
+};
+use ethereum_types::H256;
+use futures::future::join_all;
 use num_traits::{FromPrimitive, ToPrimitive};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use secp256k1::{PublicKey, SecretKey, SECP256K1};
 use std::{
-    collections::{btree_map::Entry, hash_map::Entry as HashMapEntry, BTreeMap, HashMap, HashSet},
+    collections::{HashMap, HashSet},
     fmt::Debug,
+    net::{IpAddr, SocketAddr},
+    path::Path,
     str::FromStr,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 use task_group::TaskGroup;
 use tokio::{
     sync::{
         broadcast::{channel as broadcast, Sender as BroadcastSender},
-        mpsc::{channel, Sender},
-        Mutex as AsyncMutex,
+        watch,
     },
     time::sleep,
 };
-use tokio_stream::{StreamExt, StreamMap};
-use tonic::transport::Server;
+use tokio_stream::StreamMap;
+use tonic::transport::{NamedService, Server};
 use tracing::*;
 use tracing_subscriber::{prelude::*, EnvFilter};
 use trust_dns_resolver::{config::*, TokioAsyncResolver};
 
 const FRAME_SIZE: u32 = 2097120;
 
+mod block_tracker;
 mod config;
 mod eth;
 mod grpc;
+mod health;
+mod known_peers;
+mod message_log;
+mod metrics;
+mod node_key;
+mod outbound;
+mod proto;
+mod rate_limit;
+mod reload;
+mod reputation;
+mod requests;
+mod security;
 mod services;
+mod shutdown;
+mod trusted_peers;
 mod types;
-
-type OutboundSender = Sender<OutboundEvent>;
-type OutboundReceiver = Arc<AsyncMutex<BoxStream<'static, OutboundEvent>>>;
+mod validation;
 
 pub const BUFFERING_FACTOR: usize = 5;
+/// How long to wait for a slot in a peer's outbound queue before dropping the message. Gossip does not wait.
+pub const DELIVERY_TIMEOUT: Duration = Duration::from_secs(2);
+pub const KNOWN_PEERS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Number of transaction hashes remembered per peer to not send it transactions it already knows, same as geth.
+pub const MAX_KNOWN_TRANSACTIONS: usize = 32768;
+/// Number of transaction hashes remembered across all peers, shared out evenly up to `MAX_KNOWN_TRANSACTIONS` each.
+pub const KNOWN_TRANSACTIONS_BUDGET: usize = 1 << 21;
+/// Number of block hashes remembered per peer to never announce a block to it twice.
+pub const MAX_KNOWN_BLOCKS: usize = 1024;
+/// How long to wait on shutdown for peers to get our disconnect before closing the gRPC server.
+pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// How long to wait on shutdown for gRPC calls to finish.
+pub const GRPC_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Clone)]
 struct Pipes {
-    sender: OutboundSender,
-    receiver: OutboundReceiver,
+    queue: Arc<OutboundQueue>,
 }
 
-#[derive(Clone, Debug, Default)]
-struct BlockTracker {
-    block_by_peer: HashMap<devp2p::PeerIdHash, u64>,
-    peers_by_block: BTreeMap<u64, HashSet<devp2p::PeerIdHash>>,
+/// Connection details of a peer.
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    id: PeerId,
+    eth_version: EthProtocolVersion,
+    capabilities: HashMap<CapabilityName, CapabilityVersion>,
+    connection: PeerConnectionInfo,
+    connected_at: Instant,
+    /// Last status message received from the peer.
+    status: Option<StatusMessage>,
 }
 
-impl BlockTracker {
-    fn set_block_number(&mut self, peer: devp2p::PeerIdHash, block: u64, force_create: bool) {
-        match self.block_by_peer.entry(peer) {
-            HashMapEntry::Vacant(e) => {
-                if force_create {
-                    e.insert(block);
-                } else {
-                    return;
-                }
-            }
-            HashMapEntry::Occupied(mut e) => {
-                let old_block = std::mem::replace(e.get_mut(), block);
-                if let Entry::Occupied(mut entry) = self.peers_by_block.entry(old_block) {
-                    entry.get_mut().remove(&peer);
+impl PeerInfo {
+    /// Address the peer accepts connections at. Only known for peers we dialed,
+    /// as the remote port of inbound connections is ephemeral.
+    fn dial_addr(&self) -> Option<SocketAddr> {
+        if self.connection.direction == ConnectionDirection::Outbound {
+            self.connection.remote_addr
+        } else {
+            None
+        }
+    }
+}
 
-                    if entry.get().is_empty() {
-                        entry.remove();
-                    }
-                }
-            }
+/// Result of handing an outbound event to a peer's queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Peer's queue accepted the event.
+    Accepted,
+    /// Peer's queue stayed full for `DELIVERY_TIMEOUT`, or was full at once for gossip. Event dropped.
+    TimedOut,
+    /// Peer is not connected anymore, event dropped.
+    PeerGone,
+}
+
+#[derive(Debug, Default)]
+pub struct DeliveryStats {
+    accepted: AtomicU64,
+    timed_out: AtomicU64,
+    peer_gone: AtomicU64,
+    /// Queued gossip dropped to make room for newer events.
+    shed: AtomicU64,
+    /// Messages not sent because the peer's eth version cannot represent them.
+    skipped: AtomicU64,
+}
+
+impl DeliveryStats {
+    fn record(&self, delivery: Delivery) {
+        match delivery {
+            Delivery::Accepted => &self.accepted,
+            Delivery::TimedOut => &self.timed_out,
+            Delivery::PeerGone => &self.peer_gone,
         }
+        .fetch_add(1, Ordering::Relaxed);
+    }
 
-        self.peers_by_block.entry(block).or_default().insert(peer);
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
     }
 
-    fn remove_peer(&mut self, peer: devp2p::PeerIdHash) {
-        if let Some(block) = self.block_by_peer.remove(&peer) {
-            if let Entry::Occupied(mut entry) = self.peers_by_block.entry(block) {
-                entry.get_mut().remove(&peer);
+    pub fn timed_out(&self) -> u64 {
+        self.timed_out.load(Ordering::Relaxed)
+    }
 
-                if entry.get().is_empty() {
-                    entry.remove();
-                }
-            }
-        }
+    pub fn peer_gone(&self) -> u64 {
+        self.peer_gone.load(Ordering::Relaxed)
     }
 
-    fn peers_with_min_block(&self, block: u64) -> HashSet<devp2p::PeerIdHash> {
-        self.peers_by_block
-            .range(block..)
-            .flat_map(|(_, v)| v)
-            .copied()
-            .collect()
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    pub fn on_skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
     }
 }
 
@@ -115,78 +176,508 @@ impl BlockTracker {
 pub struct CapabilityServerImpl {
     #[educe(Debug(ignore))]
     peer_pipes: Arc<RwLock<HashMap<devp2p::PeerIdHash, Pipes>>>,
+    peer_info: Arc<RwLock<HashMap<devp2p::PeerIdHash, PeerInfo>>>,
     block_tracker: Arc<RwLock<BlockTracker>>,
 
     status_message: Arc<RwLock<Option<FullStatusData>>>,
-    protocol_version: EthProtocolVersion,
+    protocol_versions: Vec<EthProtocolVersion>,
     valid_peers: Arc<RwLock<HashSet<devp2p::PeerIdHash>>>,
 
     data_sender: BroadcastSender<InboundMessage>,
+    message_log: Arc<MessageLog>,
     peers_status_sender: BroadcastSender<PeersReply>,
 
     no_new_peers: Arc<AtomicBool>,
     peer_id_cache: Arc<RwLock<HashMap<devp2p::PeerId, devp2p::PeerIdHash>>>,
+
+    reputation: Arc<Mutex<Reputation>>,
+    node_filter: Arc<Mutex<dyn NodeFilter>>,
+    known_peers: Arc<Mutex<KnownPeers>>,
+    requests: Arc<Mutex<RequestTracker>>,
+    rate_limiter: Mutex<RateLimiter>,
+    known_transactions: Mutex<HashMap<devp2p::PeerIdHash, H256LruSet>>,
+    known_blocks: Mutex<HashMap<devp2p::PeerIdHash, H256LruSet>>,
+    trusted_peers: Mutex<TrustedPeers>,
+
+    outbound_queue_capacity: usize,
+    delivery_stats: DeliveryStats,
+    metrics: Arc<Metrics>,
+    shutdown: ShutdownSignal,
 }
 
 impl CapabilityServerImpl {
-    fn setup_peer(&self, peer: devp2p::PeerIdHash, p: Pipes) {
+    fn setup_peer(&self, peer: devp2p::PeerIdHash, p: Pipes, info: PeerInfo) {
+        let max_peers = self.node_filter.lock().max_peers().max(1);
+        let known_transactions_capacity =
+            (KNOWN_TRANSACTIONS_BUDGET / max_peers).clamp(1, MAX_KNOWN_TRANSACTIONS);
+
         let mut pipes = self.peer_pipes.write();
+        let mut peer_info = self.peer_info.write();
         let mut block_tracker = self.block_tracker.write();
 
         assert!(pipes.insert(peer, p).is_none());
+        peer_info.insert(peer, info);
         block_tracker.set_block_number(peer, 0, true);
+        self.known_transactions
+            .lock()
+            .insert(peer, H256LruSet::new(known_transactions_capacity));
+        self.known_blocks
+            .lock()
+            .insert(peer, H256LruSet::new(MAX_KNOWN_BLOCKS));
     }
 
     fn get_pipes(&self, peer: devp2p::PeerIdHash) -> Option<Pipes> {
         self.peer_pipes.read().get(&peer).cloned()
     }
 
-    pub fn sender(&self, peer: devp2p::PeerIdHash) -> Option<OutboundSender> {
-        self.peer_pipes
+    /// Negotiated eth protocol version of the peer.
+    pub fn peer_eth_version(&self, peer: devp2p::PeerIdHash) -> Option<EthProtocolVersion> {
+        self.peer_info
             .read()
             .get(&peer)
-            .map(|pipes| pipes.sender.clone())
+            .map(|info| info.eth_version)
     }
 
-    fn receiver(&self, peer: devp2p::PeerIdHash) -> Option<OutboundReceiver> {
+    /// Highest eth protocol version we serve.
+    pub fn max_protocol_version(&self) -> EthProtocolVersion {
+        self.protocol_versions
+            .iter()
+            .copied()
+            .max()
+            .expect("at least one protocol version is served")
+    }
+
+    fn outbound_queue(&self, peer: devp2p::PeerIdHash) -> Option<Arc<OutboundQueue>> {
         self.peer_pipes
             .read()
             .get(&peer)
-            .map(|pipes| pipes.receiver.clone())
+            .map(|pipes| pipes.queue.clone())
+    }
+
+    /// Number of events waiting in the peer's outbound queue.
+    pub fn outbound_queue_depth(&self, peer: devp2p::PeerIdHash) -> usize {
+        self.outbound_queue(peer).map_or(0, |queue| queue.depth())
+    }
+
+    /// Start tracking the event if it is a request the peer should answer, and send it under a
+    /// request ID of our own. Returns that request ID.
+    fn track_request(&self, peer: devp2p::PeerIdHash, event: &mut OutboundEvent) -> Option<u64> {
+        let (id, data) = match event {
+            OutboundEvent::Message {
+                message: Message { id, data },
+                ..
+            } => (EthMessageId::from_usize(*id)?, data),
+            OutboundEvent::Disconnect { .. } => return None,
+        };
+        if id.response_id().is_none() || !self.peer_eth_version(peer)?.has_request_ids() {
+            return None;
+        }
+
+        let core_request_id = request_id(data).ok()?;
+        let request_id = self.requests.lock().on_request(peer, id, core_request_id)?;
+        match with_request_id(data, request_id) {
+            Ok(rewritten) => *data = rewritten,
+            Err(_) => {
+                self.requests.lock().cancel(peer, request_id);
+                return None;
+            }
+        }
+
+        Some(request_id)
+    }
+
+    /// Queue an event for the peer, waiting at most `DELIVERY_TIMEOUT` if its queue is full.
+    pub async fn deliver(&self, peer: devp2p::PeerIdHash, mut event: OutboundEvent) -> Delivery {
+        let request_id = self.track_request(peer, &mut event);
+        let delivery = match self.outbound_queue(peer) {
+            Some(queue) => match queue.push_timeout(event, DELIVERY_TIMEOUT).await {
+                Ok(shed) => {
+                    if let Some(shed) = shed {
+                        self.delivery_stats.shed.fetch_add(1, Ordering::Relaxed);
+                        self.forget_gossip(peer, &shed);
+                    }
+                    Delivery::Accepted
+                }
+                Err(PushError::Full(_)) => Delivery::TimedOut,
+                Err(PushError::Closed(_)) => Delivery::PeerGone,
+            },
+            None => Delivery::PeerGone,
+        };
+        self.delivery_stats.record(delivery);
+        if let Some(request_id) = request_id {
+            if delivery != Delivery::Accepted {
+                self.requests.lock().cancel(peer, request_id);
+            }
+        }
+
+        delivery
+    }
+
+    pub fn request_stats(&self, peer: devp2p::PeerIdHash) -> RequestStats {
+        self.requests.lock().stats(peer)
+    }
+
+    /// Penalize peers for requests they did not answer within `REQUEST_TIMEOUT`.
+    pub async fn expire_requests(&self) {
+        let expired = self.requests.lock().expire(Instant::now());
+        for (peer, response_id, request_id) in expired {
+            debug!(
+                "Peer {} did not answer request {} with {:?} in time",
+                peer, request_id, response_id
+            );
+            if self.report_peer(peer, ReputationChange::Timeout) != Verdict::Keep {
+                self.deliver(
+                    peer,
+                    OutboundEvent::Disconnect {
+                        reason: DisconnectReason::UselessPeer,
+                    },
+                )
+                .await;
+            }
+        }
+    }
+
+    pub fn delivery_stats(&self) -> &DeliveryStats {
+        &self.delivery_stats
     }
 
     #[instrument(name = "CapabilityServerImpl.teardown_peer", skip(self))]
     fn teardown_peer(&self, peer: devp2p::PeerIdHash) {
         let mut pipes = self.peer_pipes.write();
+        let mut peer_info = self.peer_info.write();
         let mut block_tracker = self.block_tracker.write();
         let mut valid_peers = self.valid_peers.write();
 
-        pipes.remove(&peer);
+        if let Some(pipes) = pipes.remove(&peer) {
+            pipes.queue.close();
+        }
+        peer_info.remove(&peer);
         block_tracker.remove_peer(peer);
-        valid_peers.remove(&peer);
-
-        let send_status_result =
-            self.peers_status_sender
-                .send(ethereum_interfaces::sentry::PeersReply {
-                    peer_id: Some(peer.into()),
-                    event: ethereum_interfaces::sentry::peers_reply::PeerEvent::Disconnect as i32,
-                });
+        let was_valid = valid_peers.remove(&peer);
+        self.requests.lock().remove_peer(peer);
+        self.rate_limiter.lock().remove_peer(peer);
+        self.known_transactions.lock().remove(&peer);
+        self.known_blocks.lock().remove(&peer);
+
+        // Peers dropped by fork ID re-validation have been reported already.
+        if was_valid {
+            self.send_peer_event(peer, PeerEvent::Disconnect);
+        }
+    }
+
+    fn send_peer_event(&self, peer: devp2p::PeerIdHash, event: PeerEvent) {
+        let send_status_result = self.peers_status_sender.send(PeersReply {
+            peer_id: Some(peer.into()),
+            event: event as i32,
+        });
         if send_status_result.is_err() {
             debug!("No subscribers to report peer status to");
         }
     }
 
+    /// Disconnect every peer, returning once the disconnects are queued.
+    pub async fn disconnect_all(&self, reason: DisconnectReason) {
+        join_all(
+            self.all_peers()
+                .into_iter()
+                .map(|peer| self.deliver(peer, OutboundEvent::Disconnect { reason })),
+        )
+        .await;
+    }
+
     pub fn all_peers(&self) -> HashSet<devp2p::PeerIdHash> {
         self.peer_pipes.read().keys().copied().collect()
     }
 
+    /// Connection details of all connected peers.
+    pub fn all_peer_info(&self) -> Vec<(devp2p::PeerIdHash, PeerInfo)> {
+        self.peer_info
+            .read()
+            .iter()
+            .map(|(&peer, info)| (peer, info.clone()))
+            .collect()
+    }
+
+    pub fn peer_block_number(&self, peer: devp2p::PeerIdHash) -> Option<u64> {
+        self.block_tracker.read().block_number(peer)
+    }
+
+    /// Positions of transactions among `hashes` that the peer has neither announced to us nor got from us.
+    pub fn unknown_transactions(&self, peer: devp2p::PeerIdHash, hashes: &[H256]) -> Vec<usize> {
+        let known_transactions = self.known_transactions.lock();
+        let known = known_transactions.get(&peer);
+        hashes
+            .iter()
+            .enumerate()
+            .filter(|(_, hash)| !known.map_or(false, |known| known.contains(hash)))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    pub fn mark_transactions_known(
+        &self,
+        peer: devp2p::PeerIdHash,
+        hashes: impl IntoIterator<Item = H256>,
+    ) {
+        if let Some(known) = self.known_transactions.lock().get_mut(&peer) {
+            for hash in hashes {
+                known.insert(hash);
+            }
+        }
+    }
+
+    /// Undo marking transactions known to the peer when it did not get them after all.
+    pub fn forget_transactions(
+        &self,
+        peer: devp2p::PeerIdHash,
+        hashes: impl IntoIterator<Item = H256>,
+    ) {
+        if let Some(known) = self.known_transactions.lock().get_mut(&peer) {
+            for hash in hashes {
+                known.remove(&hash);
+            }
+        }
+    }
+
+    /// Valid peers that have neither announced the block to us nor got it from us, marked as
+    /// knowing it so that concurrent announcements of the same block never pick them again.
+    pub fn claim_peers_without_block(&self, hash: H256) -> Vec<devp2p::PeerIdHash> {
+        let valid_peers = self.valid_peers.read().clone();
+        let mut known_blocks = self.known_blocks.lock();
+        valid_peers
+            .into_iter()
+            .filter(|peer| match known_blocks.get_mut(peer) {
+                Some(known) if !known.contains(&hash) => {
+                    known.insert(hash);
+                    true
+                }
+                _ => false,
+            })
+            .collect()
+    }
+
+    pub fn mark_block_known(&self, peer: devp2p::PeerIdHash, hash: H256) {
+        if let Some(known) = self.known_blocks.lock().get_mut(&peer) {
+            known.insert(hash);
+        }
+    }
+
+    /// Undo marking blocks known to the peer when it did not get them after all.
+    pub fn forget_blocks(&self, peer: devp2p::PeerIdHash, hashes: impl IntoIterator<Item = H256>) {
+        if let Some(known) = self.known_blocks.lock().get_mut(&peer) {
+            for hash in hashes {
+                known.remove(&hash);
+            }
+        }
+    }
+
+    /// Undo marking what a shed announcement carried as known to the peer.
+    fn forget_gossip(&self, peer: devp2p::PeerIdHash, event: &OutboundEvent) {
+        let (id, data) = match event {
+            OutboundEvent::Message {
+                message: Message { id, data },
+                ..
+            } => (EthMessageId::from_usize(*id), data),
+            OutboundEvent::Disconnect { .. } => return,
+        };
+
+        match id {
+            Some(EthMessageId::NewBlock) => {
+                if let Ok(block) = NewBlockAnnouncement::decode(data) {
+                    self.forget_blocks(peer, [block.hash]);
+                }
+            }
+            Some(EthMessageId::NewBlockHashes) => {
+                if let Ok(blocks) = rlp::Rlp::new(data).as_list::<BlockHashNumber>() {
+                    self.forget_blocks(peer, blocks.into_iter().map(|block| block.hash));
+                }
+            }
+            Some(EthMessageId::Transactions) => {
+                if let Ok(hashes) = transaction_hashes(data) {
+                    self.forget_transactions(peer, hashes);
+                }
+            }
+            Some(EthMessageId::NewPooledTransactionHashes) => {
+                if let Ok(announcement) = PooledTransactionAnnouncement::decode(data) {
+                    self.forget_transactions(peer, announcement.hashes().iter().copied());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn peer_head(&self, peer: devp2p::PeerIdHash) -> Option<PeerHead> {
+        self.block_tracker.read().head(peer)
+    }
+
+    /// Whether the node is connected and past the RLPx handshake.
+    pub fn is_connected(&self, id: PeerId) -> bool {
+        let peer = self.get_hash(id);
+        self.peer_pipes.read().contains_key(&peer)
+    }
+
+    /// Replace the peers that bypass bans and the peer limit and are kept connected.
+    pub fn set_trusted_peers(&self, peers: HashMap<PeerId, SocketAddr>) {
+        let mut trusted_peers = self.trusted_peers.lock();
+        trusted_peers.set(peers, Instant::now());
+        self.node_filter.lock().set_trusted(trusted_peers.ids());
+    }
+
+    /// Disconnect and forget peers that are banned, trusted or not.
+    pub async fn disconnect_banned(&self) {
+        let peers = self.all_peer_info();
+        let banned = {
+            let node_filter = self.node_filter.lock();
+            peers
+                .into_iter()
+                .filter(|(_, info)| {
+                    node_filter.is_banned(info.id)
+                        || info
+                            .connection
+                            .remote_addr
+                            .map_or(false, |addr| node_filter.is_ip_banned(addr.ip()))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        {
+            let mut known_peers = self.known_peers.lock();
+            for (_, info) in &banned {
+                known_peers.forget(info.id);
+            }
+        }
+        join_all(banned.into_iter().map(|(peer, _)| {
+            self.deliver(
+                peer,
+                OutboundEvent::Disconnect {
+                    reason: DisconnectReason::DisconnectRequested,
+                },
+            )
+        }))
+        .await;
+    }
+
     pub fn connected_peers(&self) -> usize {
         self.valid_peers.read().len()
     }
 
-    pub fn set_status(&self, message: FullStatusData) {
+    pub fn has_status(&self) -> bool {
+        self.status_message.read().is_some()
+    }
+
+    /// Update our status and disconnect peers whose fork ID is incompatible with it.
+    pub async fn set_status(&self, message: FullStatusData) {
+        self.block_tracker
+            .write()
+            .set_own_block(message.status.best_block);
         *self.status_message.write() = Some(message);
         self.no_new_peers.store(false, Ordering::SeqCst);
+
+        let incompatible = self.invalidate_incompatible_peers();
+        join_all(incompatible.into_iter().map(|peer| {
+            self.deliver(
+                peer,
+                OutboundEvent::Disconnect {
+                    reason: DisconnectReason::UselessPeer,
+                },
+            )
+        }))
+        .await;
+    }
+
+    /// Re-validate fork IDs of valid peers against the current fork filter, dropping failed ones from valid peers.
+    fn invalidate_incompatible_peers(&self) -> Vec<devp2p::PeerIdHash> {
+        let fork_filter =
+            if let Some(FullStatusData { fork_filter, .. }) = &*self.status_message.read() {
+                fork_filter.clone()
+            } else {
+                return Vec::new();
+            };
+
+        let valid_peers = self.valid_peers.read().clone();
+        let incompatible = {
+            let peer_info = self.peer_info.read();
+            valid_peers
+                .into_iter()
+                .filter(|peer| {
+                    let status = peer_info.get(peer).and_then(|info| info.status.as_ref());
+                    match status.map(|status| fork_filter.validate(status.fork_id)) {
+                        Some(Err(reason)) => {
+                            debug!(
+                                "Peer {} has become incompatible with our fork ID: {:?}",
+                                peer, reason
+                            );
+                            true
+                        }
+                        _ => false,
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut valid_peers = self.valid_peers.write();
+        let incompatible = incompatible
+            .into_iter()
+            .filter(|peer| valid_peers.remove(peer))
+            .collect::<Vec<_>>();
+        drop(valid_peers);
+
+        for &peer in &incompatible {
+            self.send_peer_event(peer, PeerEvent::Disconnect);
+        }
+
+        incompatible
+    }
+
+    fn is_trusted(&self, peer: devp2p::PeerIdHash) -> bool {
+        let id = self.peer_info.read().get(&peer).map(|info| info.id);
+        id.map_or(false, |id| self.node_filter.lock().is_trusted(id))
+    }
+
+    /// Adjust peer's reputation, banning it if it fell too low.
+    #[instrument(name = "CapabilityServerImpl.report_peer", skip(self))]
+    pub fn report_peer(&self, peer: devp2p::PeerIdHash, change: ReputationChange) -> Verdict {
+        let id = if let Some(info) = self.peer_info.read().get(&peer) {
+            info.id
+        } else {
+            return Verdict::Keep;
+        };
+
+        // Trusted peers are never evicted by scoring.
+        if self.node_filter.lock().is_trusted(id) {
+            return Verdict::Keep;
+        }
+
+        let verdict = self.reputation.lock().report(id, change);
+        if verdict != Verdict::Keep {
+            self.known_peers.lock().forget(id);
+        }
+        if verdict == Verdict::Ban {
+            debug!("Banning peer for {:?}", BAN_DURATION);
+            self.node_filter
+                .lock()
+                .ban(id, Some(Instant::now() + BAN_DURATION));
+        }
+
+        verdict
+    }
+
+    /// Bump last seen time of connected peers worth redialing after restart.
+    fn refresh_known_peers(&self) {
+        let valid_peers = self.valid_peers.read().clone();
+        let peer_info = self.peer_info.read();
+        let mut known_peers = self.known_peers.lock();
+        for info in valid_peers.iter().filter_map(|peer| peer_info.get(peer)) {
+            if let Some(addr) = info.dial_addr() {
+                known_peers.seen(info.id, addr);
+            }
+        }
+    }
+
+    pub async fn save_known_peers(&self, path: &Path) -> anyhow::Result<()> {
+        self.refresh_known_peers();
+        let known_peers = self.known_peers.lock().clone();
+        known_peers.save(path).await
     }
 
     #[instrument(name = "CapabilityServerImpl.handle_event", skip(self, event))]
@@ -198,60 +689,258 @@ impl CapabilityServerImpl {
         match event {
             InboundEvent::Disconnect { reason } => {
                 debug!("Peer disconnect (reason: {:?}), tearing down peer.", reason);
+                self.metrics.on_disconnect(reason);
+                if let Some(DisconnectReason::PingTimeout) = reason {
+                    self.report_peer(peer, ReputationChange::Timeout);
+                }
                 self.teardown_peer(peer);
             }
-            InboundEvent::Message {
-                message: Message { id, data },
-                ..
-            } => {
+            InboundEvent::Message { message, .. } => {
+                self.metrics.on_inbound(&message);
+                let Message { id, mut data } = message;
+                let eth_version = if let Some(v) = self.peer_eth_version(peer) {
+                    v
+                } else {
+                    debug!("Message from unknown peer");
+                    return Ok(None);
+                };
                 let valid_peer = self.valid_peers.read().contains(&peer);
                 let message_id = EthMessageId::from_usize(id);
                 match message_id {
                     None => {
                         debug!("Unknown message");
                     }
+                    Some(id) if !id.is_supported_by(eth_version) => {
+                        debug!(
+                            "Message {:?} is not part of eth/{}, kicking peer",
+                            id, eth_version as usize
+                        );
+
+                        return Err(DisconnectReason::ProtocolBreach);
+                    }
                     Some(EthMessageId::Status) => {
                         let v = rlp::decode::<StatusMessage>(&data).map_err(|e| {
                             debug!("Failed to decode status message: {}! Kicking peer.", e);
+                            self.metrics.on_handshake(Handshake::MalformedStatus);
 
                             DisconnectReason::ProtocolBreach
                         })?;
 
                         debug!("Decoded status message: {:?}", v);
 
+                        let known_peer = if let Some(info) = self.peer_info.write().get_mut(&peer) {
+                            info.status = Some(v.clone());
+                            info.dial_addr().map(|addr| (info.id, addr))
+                        } else {
+                            None
+                        };
+                        self.block_tracker.write().set_head(
+                            peer,
+                            PeerHead {
+                                hash: v.best_hash,
+                                total_difficulty: v.total_difficulty,
+                            },
+                        );
+
                         let status_data = self.status_message.read();
                         let mut valid_peers = self.valid_peers.write();
                         if let Some(FullStatusData { fork_filter, .. }) = &*status_data {
                             fork_filter.validate(v.fork_id).map_err(|reason| {
                                 debug!("Kicking peer with incompatible fork ID: {:?}", reason);
+                                self.metrics.on_handshake(Handshake::IncompatibleFork);
 
                                 DisconnectReason::UselessPeer
                             })?;
 
                             valid_peers.insert(peer);
-
-                            let send_status_result =
-                                self.peers_status_sender
-                                    .send(ethereum_interfaces::sentry::PeersReply {
-                                    peer_id: Some(peer.into()),
-                                    event:
-                                        ethereum_interfaces::sentry::peers_reply::PeerEvent::Connect
-                                            as i32,
-                                });
-                            if send_status_result.is_err() {
-                                debug!("No subscribers to report peer status to");
+                            if let Some((id, addr)) = known_peer {
+                                self.known_peers.lock().seen(id, addr);
                             }
+
+                            self.metrics.on_handshake(Handshake::Accepted);
+                            self.send_peer_event(peer, PeerEvent::Connect);
                         }
                     }
                     Some(inbound_id) if valid_peer => {
-                        if self
-                            .data_sender
-                            .send(InboundMessage {
-                                id: sentry::MessageId::from(inbound_id) as i32,
-                                data,
-                                peer_id: Some(peer.into()),
-                            })
-                            .is_err()
+                        let admission =
+                            self.rate_limiter
+                                .lock()
+                                .admit(peer, inbound_id, Instant::now());
+                        match admission {
+                            Admission::Accept => {}
+                            Admission::Drop => {
+                                trace!("Peer exceeded its {:?} budget, dropping", inbound_id);
+                                return Ok(None);
+                            }
+                            // Trusted peers are never kicked for flooding, only their excess is dropped.
+                            Admission::Disconnect if self.is_trusted(peer) => {
+                                trace!(
+                                    "Trusted peer exceeded its {:?} budget, dropping",
+                                    inbound_id
+                                );
+                                return Ok(None);
+                            }
+                            Admission::Disconnect => {
+                                debug!("Peer keeps exceeding its message budgets! Kicking peer.");
+                                return Err(DisconnectReason::ProtocolBreach);
+                            }
+                        }
+
+                        // eth/68 announcement reduced to the layout `sentry.MessageId` has an ID for.
+                        let mut hash_only = None;
+
+                        validate_message(inbound_id, eth_version, &data).map_err(|e| {
+                            debug!("Malformed {:?} message: {}! Kicking peer.", inbound_id, e);
+
+                            DisconnectReason::ProtocolBreach
+                        })?;
+
+                        if inbound_id == EthMessageId::NewPooledTransactionHashes {
+                            let decoded =
+                                PooledTransactionAnnouncement::decode_for(&data, eth_version);
+                            let announcement = decoded.map_err(|e| {
+                                debug!(
+                                    "Failed to decode transaction announcement: {}! Kicking peer.",
+                                    e
+                                );
+
+                                DisconnectReason::ProtocolBreach
+                            })?;
+                            self.mark_transactions_known(
+                                peer,
+                                announcement.hashes().iter().copied(),
+                            );
+                            if let PooledTransactionAnnouncement::Typed(announcement) = announcement
+                            {
+                                hash_only = Some(
+                                    rlp::encode_list::<H256, _>(&announcement.hashes).freeze(),
+                                );
+                            }
+                        }
+
+                        if inbound_id == EthMessageId::Transactions {
+                            let hashes = transaction_hashes(&data).map_err(|e| {
+                                debug!("Failed to decode transactions: {}! Kicking peer.", e);
+
+                                DisconnectReason::ProtocolBreach
+                            })?;
+                            self.mark_transactions_known(peer, hashes);
+                        }
+
+                        if inbound_id == EthMessageId::PooledTransactions {
+                            let hashes =
+                                pooled_transaction_hashes(&data, eth_version).map_err(|e| {
+                                    debug!(
+                                        "Failed to decode pooled transactions: {}! Kicking peer.",
+                                        e
+                                    );
+
+                                    DisconnectReason::ProtocolBreach
+                                })?;
+                            self.mark_transactions_known(peer, hashes);
+                        }
+
+                        if inbound_id == EthMessageId::NewBlock {
+                            let block = NewBlockAnnouncement::decode(&data).map_err(|e| {
+                                debug!("Failed to decode new block: {}! Kicking peer.", e);
+
+                                DisconnectReason::ProtocolBreach
+                            })?;
+                            self.mark_block_known(peer, block.hash);
+                            self.block_tracker.write().on_announcement(
+                                peer,
+                                block.hash,
+                                block.number,
+                                Some(block.total_difficulty),
+                            );
+                        }
+
+                        if inbound_id == EthMessageId::NewBlockHashes {
+                            let blocks = rlp::Rlp::new(&data)
+                                .as_list::<BlockHashNumber>()
+                                .map_err(|e| {
+                                    debug!(
+                                        "Failed to decode new block hashes: {}! Kicking peer.",
+                                        e
+                                    );
+
+                                    DisconnectReason::ProtocolBreach
+                                })?;
+                            for block in &blocks {
+                                self.mark_block_known(peer, block.hash);
+                            }
+                            let mut block_tracker = self.block_tracker.write();
+                            for block in blocks {
+                                block_tracker.on_announcement(peer, block.hash, block.number, None);
+                            }
+                        }
+
+                        if inbound_id.is_response() && eth_version.has_request_ids() {
+                            let request_id = request_id(&data).map_err(|e| {
+                                debug!("Failed to decode request ID: {}! Kicking peer.", e);
+
+                                DisconnectReason::ProtocolBreach
+                            })?;
+
+                            match self
+                                .requests
+                                .lock()
+                                .on_response(peer, inbound_id, request_id)
+                            {
+                                ResponseMatch::Answered {
+                                    latency,
+                                    core_request_id,
+                                } => {
+                                    trace!("Request {} answered in {:?}", request_id, latency);
+                                    // Hand the response back under the ID the core sent the request with.
+                                    data =
+                                        with_request_id(&data, core_request_id).map_err(|e| {
+                                            debug!(
+                                                "Failed to decode response: {}! Kicking peer.",
+                                                e
+                                            );
+
+                                            DisconnectReason::ProtocolBreach
+                                        })?;
+                                }
+                                ResponseMatch::Late => {
+                                    // The core has given up on the request already.
+                                    debug!(
+                                        "Request {} answered after timing out, dropping response",
+                                        request_id
+                                    );
+                                    return Ok(None);
+                                }
+                                ResponseMatch::Unsolicited => {
+                                    debug!(
+                                        "Unsolicited {:?} with request ID {}! Kicking peer.",
+                                        inbound_id, request_id
+                                    );
+
+                                    return Err(DisconnectReason::ProtocolBreach);
+                                }
+                            }
+                        }
+
+                        let message = InboundMessage {
+                            id: proto_message_id(inbound_id, eth_version) as i32,
+                            data,
+                            peer_id: Some(peer.into()),
+                        };
+                        // The extended API carries the typed layout under its own ID.
+                        let (logged, message) = match hash_only {
+                            Some(data) => (
+                                InboundMessage {
+                                    id: ExtMessageId::NewPooledTransactionHashes68 as i32,
+                                    ..message.clone()
+                                },
+                                InboundMessage { data, ..message },
+                            ),
+                            None => (message.clone(), message),
+                        };
+                        self.message_log.push(logged, !inbound_id.is_gossip());
+                        if self.data_sender.send(message).is_err()
+                            && !self.message_log.has_subscribers()
                         {
                             warn!("no connected sentry, dropping status and peer");
                             *self.status_message.write() = None;
@@ -292,17 +981,21 @@ impl CapabilityServer for CapabilityServerImpl {
         &self,
         p2p_peer_id: PeerId,
         caps: HashMap<CapabilityName, CapabilityVersion>,
+        connection: PeerConnectionInfo,
     ) {
         let peer = self.get_hash(p2p_peer_id);
+        let eth_version = caps
+            .get(&capability_name())
+            .copied()
+            .and_then(EthProtocolVersion::from_usize)
+            .expect("peer without this cap would have been disconnected");
         let first_events = if let Some(FullStatusData {
             status,
             fork_filter,
         }) = &*self.status_message.read()
         {
             let status_message = StatusMessage {
-                protocol_version: *caps
-                    .get(&capability_name())
-                    .expect("peer without this cap would have been disconnected"),
+                protocol_version: eth_version as usize,
                 network_id: status.network_id,
                 total_difficulty: status.total_difficulty,
                 best_hash: status.best_hash,
@@ -318,25 +1011,27 @@ impl CapabilityServer for CapabilityServerImpl {
                 },
             }]
         } else {
+            self.metrics.on_handshake(Handshake::NotReady);
             vec![OutboundEvent::Disconnect {
                 reason: DisconnectReason::DisconnectRequested,
             }]
         };
 
-        let (sender, mut receiver) = channel(1);
+        let queue = Arc::new(OutboundQueue::new(self.outbound_queue_capacity));
+        for event in first_events {
+            // Control events always fit.
+            let _ = queue.try_push(event);
+        }
         self.setup_peer(
             peer,
-            Pipes {
-                sender,
-                receiver: Arc::new(AsyncMutex::new(Box::pin(stream! {
-                    for event in first_events {
-                        yield event;
-                    }
-
-                    while let Some(event) = receiver.recv().await {
-                        yield event;
-                    }
-                }))),
+            Pipes { queue },
+            PeerInfo {
+                id: p2p_peer_id,
+                eth_version,
+                capabilities: caps,
+                connection,
+                connected_at: Instant::now(),
+                status: None,
             },
         );
     }
@@ -345,32 +1040,40 @@ impl CapabilityServer for CapabilityServerImpl {
     async fn on_peer_event(&self, p2p_peer_id: PeerId, event: InboundEvent) {
         debug!("Received message");
         let peer = self.get_hash(p2p_peer_id);
-        if let Some(ev) = self.handle_event(peer, event).transpose() {
-            let _ = self
-                .sender(peer)
-                .unwrap()
-                .send(match ev {
+        let res = self.handle_event(peer, event);
+        if let Err(reason) = &res {
+            if let Some(change) = ReputationChange::for_disconnect(*reason) {
+                self.report_peer(peer, change);
+            }
+        }
+        if let Some(ev) = res.transpose() {
+            self.deliver(
+                peer,
+                match ev {
                     Ok(message) => OutboundEvent::Message {
                         capability_name: capability_name(),
                         message,
                     },
                     Err(reason) => OutboundEvent::Disconnect { reason },
-                })
-                .await;
+                },
+            )
+            .await;
         }
     }
 
     async fn next(&self, p2p_peer_id: PeerId) -> OutboundEvent {
         let peer = self.get_hash(p2p_peer_id);
-        self.receiver(peer)
-            .unwrap()
-            .lock()
-            .await
-            .next()
-            .await
-            .unwrap_or(OutboundEvent::Disconnect {
-                reason: DisconnectReason::DisconnectRequested,
-            })
+        let event = match self.outbound_queue(peer) {
+            Some(queue) => queue.recv().await,
+            None => None,
+        };
+
+        let event = event.unwrap_or(OutboundEvent::Disconnect {
+            reason: DisconnectReason::DisconnectRequested,
+        });
+        self.metrics.on_outbound(&event);
+
+        event
     }
 }
 
@@ -399,10 +1102,15 @@ struct OptsDiscV4 {
     discv4_cache: usize,
     discv4_concurrent_lookups: usize,
     listen_port: u16,
+    external_ip: Option<IpAddr>,
 }
 
 impl OptsDiscV4 {
-    async fn make_task(self, secret_key: &SecretKey) -> anyhow::Result<Discv4> {
+    /// Discovery task and the node whose table it searches.
+    async fn make_task(
+        self,
+        secret_key: &SecretKey,
+    ) -> anyhow::Result<(Discv4, Arc<discv4::Node>)> {
         info!("Starting discv4 at port {}", self.discv4_port);
 
         let mut bootstrap_nodes = self
@@ -423,7 +1131,7 @@ impl OptsDiscV4 {
             format!("0.0.0.0:{}", self.discv4_port).parse().unwrap(),
             *secret_key,
             bootstrap_nodes,
-            None,
+            self.external_ip,
             self.listen_port,
         )
         .await?;
@@ -431,9 +1139,9 @@ impl OptsDiscV4 {
         let task = Discv4Builder::default()
             .with_cache(self.discv4_cache)
             .with_concurrent_lookups(self.discv4_concurrent_lookups)
-            .build(node);
+            .build(node.clone());
 
-        Ok(task)
+        Ok((task, node))
     }
 }
 
@@ -478,53 +1186,87 @@ impl OptsDiscV5 {
 }
 
 struct OptsDiscStatic {
-    static_peers: Vec<NR>,
+    static_peers: watch::Receiver<HashMap<SocketAddr, PeerId>>,
     static_peers_interval: u64,
 }
 
 impl OptsDiscStatic {
     fn make_task(self) -> anyhow::Result<StaticNodes> {
-        info!("Enabling static peers: {:?}", self.static_peers);
+        if !self.static_peers.borrow().is_empty() {
+            info!("Enabling static peers: {:?}", *self.static_peers.borrow());
+        }
 
-        let task = StaticNodes::new(
-            self.static_peers
-                .iter()
-                .map(|&NR(NodeRecord { addr, id })| (addr, id))
-                .collect::<HashMap<_, _>>(),
+        let task = StaticNodes::updatable(
+            self.static_peers,
             Duration::from_millis(self.static_peers_interval),
         );
         Ok(task)
     }
 }
 
+/// Filter from `--log-filter`, falling back to `RUST_LOG` and then to our defaults.
+fn log_filter(directives: Option<&str>) -> anyhow::Result<EnvFilter> {
+    if let Some(directives) = directives {
+        return Ok(EnvFilter::try_new(directives)?);
+    }
+
+    Ok(
+        if std::env::var(EnvFilter::DEFAULT_ENV)
+            .unwrap_or_default()
+            .is_empty()
+        {
+            EnvFilter::new("ethereum_sentry=info,devp2p=info,discv4=info,discv5=info,dnsdisc=info")
+        } else {
+            EnvFilter::from_default_env()
+        },
+    )
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let opts: Opts = Opts::parse();
+    let opts: Opts = Opts::load()?;
+    let reloadable_settings = ReloadableSettings::from(&opts);
+    let restart_settings = RestartSettings::from(&opts);
     fdlimit::raise_fd_limit();
 
-    let filter = if std::env::var(EnvFilter::DEFAULT_ENV)
-        .unwrap_or_default()
-        .is_empty()
-    {
-        EnvFilter::new("ethereum_sentry=info,devp2p=info,discv4=info,discv5=info,dnsdisc=info")
-    } else {
-        EnvFilter::from_default_env()
-    };
+    let (filter, log_filter_handle) =
+        tracing_subscriber::reload::Layer::new(log_filter(opts.log_filter.as_deref())?);
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
         .with(filter)
+        .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let node_key = opts
+        .node_key
+        .as_deref()
+        .map(parse_node_key)
+        .transpose()
+        .context("Invalid node key")?;
     let secret_key;
-    if let Some(data) = opts.node_key {
-        secret_key = SecretKey::from_slice(&hex::decode(data)?)?;
+    if let Some(source) = &opts.import_node_key {
+        let path = opts
+            .node_key_file
+            .as_deref()
+            .ok_or_else(|| anyhow!("--import-node-key requires --node-key-file"))?;
+        if node_key.is_some() {
+            bail!("--import-node-key conflicts with --node-key");
+        }
+        secret_key = import_node_key(source, path).await?;
+    } else if let Some(path) = &opts.node_key_file {
+        secret_key = load_or_create_node_key(path, node_key).await?;
+    } else if let Some(node_key) = node_key {
+        secret_key = node_key;
         info!("Loaded node key from config");
     } else {
         secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
         info!("Generated new node key: {}", secret_key);
     };
+    if let Some(path) = &opts.export_node_key {
+        export_node_key(path, &secret_key).await?;
+    }
 
     let listen_addr = format!("0.0.0.0:{}", opts.listen_port);
+    let client_version = format!("sentry/v{}", env!("CARGO_PKG_VERSION"));
 
     info!("Starting Ethereum sentry");
 
@@ -543,14 +1285,20 @@ async fn main() -> anyhow::Result<()> {
         info!("Peers restricted to range {}", cidr_filter);
     }
 
+    let metrics = Arc::new(Metrics::new()?);
+
     let mut discovery_tasks: StreamMap<String, Discovery> = StreamMap::new();
+    let mut discv4_node = None;
 
     if !opts.no_discovery {
         let task_opts = OptsDnsDisc {
             address: opts.dnsdisc_address,
         };
         let task = task_opts.make_task()?;
-        discovery_tasks.insert("dnsdisc".to_string(), Box::pin(task));
+        discovery_tasks.insert(
+            "dnsdisc".to_string(),
+            metrics.count_discoveries("dnsdisc", Box::pin(task)),
+        );
 
         let task_opts = OptsDiscV4 {
             discv4_port: opts.discv4_port,
@@ -558,67 +1306,142 @@ async fn main() -> anyhow::Result<()> {
             discv4_cache: opts.discv4_cache,
             discv4_concurrent_lookups: opts.discv4_concurrent_lookups,
             listen_port: opts.listen_port,
+            external_ip: opts.external_ip,
         };
-        let task = task_opts.make_task(&secret_key).await?;
-        discovery_tasks.insert("discv4".to_string(), Box::pin(task));
+        let (task, node) = task_opts.make_task(&secret_key).await?;
+        discv4_node = Some(node);
+        discovery_tasks.insert(
+            "discv4".to_string(),
+            metrics.count_discoveries("discv4", Box::pin(task)),
+        );
 
         if opts.discv5 {
             let task_opts = OptsDiscV5 {
-                discv5_enr: opts.discv5_enr,
+                discv5_enr: opts.discv5_enr.clone(),
                 discv5_addr: opts.discv5_addr,
                 discv5_bootnodes: opts.discv5_bootnodes,
             };
             let task = task_opts.make_task(&secret_key).await?;
-            discovery_tasks.insert("discv5".to_string(), Box::pin(task));
+            discovery_tasks.insert(
+                "discv5".to_string(),
+                metrics.count_discoveries("discv5", Box::pin(task)),
+            );
         }
     }
 
-    if !opts.static_peers.is_empty() {
-        let task_opts = OptsDiscStatic {
-            static_peers: opts.static_peers,
-            static_peers_interval: opts.static_peers_interval,
-        };
-        let task = task_opts.make_task()?;
-        discovery_tasks.insert("static peers".to_string(), Box::pin(task));
+    let discovery_expected = !discovery_tasks.is_empty() || !opts.static_peers.is_empty();
+
+    // Always running, as static peers may be added on reload.
+    let (static_peers_sender, static_peers) =
+        watch::channel(reloadable_settings.static_peers.clone());
+    let task_opts = OptsDiscStatic {
+        static_peers,
+        static_peers_interval: opts.static_peers_interval,
+    };
+    let task = task_opts.make_task()?;
+    discovery_tasks.insert(
+        "static peers".to_string(),
+        metrics.count_discoveries("static peers", Box::pin(task)),
+    );
+
+    let mut priority_discovery_tasks: StreamMap<String, Discovery> = StreamMap::new();
+    let known_peers = if let Some(peers_file) = &opts.peers_file {
+        let known_peers = KnownPeers::load(peers_file).await?;
+        info!(
+            "Loaded {} known peers from {}",
+            known_peers.len(),
+            peers_file.display()
+        );
+        known_peers
+    } else {
+        KnownPeers::default()
+    };
+    if !known_peers.is_empty() {
+        let records = known_peers.node_records();
+        priority_discovery_tasks.insert(
+            "known peers".to_string(),
+            metrics.count_discoveries(
+                "known peers",
+                Box::pin(futures::stream::iter(records.into_iter().map(Ok))),
+            ),
+        );
     }
 
-    if discovery_tasks.is_empty() {
+    if !discovery_expected {
         warn!("All discovery methods are disabled, sentry will not search for peers.");
     }
 
     let tasks = Arc::new(TaskGroup::new());
 
-    let protocol_version = EthProtocolVersion::Eth66;
     let data_sender = broadcast(opts.max_peers * BUFFERING_FACTOR).0;
+    let max_peers = Arc::new(AtomicUsize::new(opts.max_peers));
+    let node_filter: Arc<Mutex<dyn NodeFilter>> =
+        Arc::new(Mutex::new(MemoryNodeFilter::new(max_peers.clone())));
     let peers_status_sender = broadcast(opts.max_peers).0;
     let no_new_peers = Arc::new(AtomicBool::new(true));
+    let (shutdown_sender, shutdown) = ShutdownSignal::new();
 
     let capability_server = Arc::new(CapabilityServerImpl {
         peer_pipes: Default::default(),
         block_tracker: Default::default(),
+        peer_info: Default::default(),
         status_message: Default::default(),
-        protocol_version,
+        protocol_versions: EthProtocolVersion::ALL.to_vec(),
         valid_peers: Default::default(),
         data_sender,
+        message_log: Arc::new(MessageLog::new(opts.message_replay_buffer)),
         peers_status_sender,
         no_new_peers: no_new_peers.clone(),
         peer_id_cache: Arc::new(RwLock::new(HashMap::new())),
+        outbound_queue_capacity: opts.outbound_queue_capacity,
+        delivery_stats: Default::default(),
+        reputation: Default::default(),
+        node_filter: node_filter.clone(),
+        known_peers: Arc::new(Mutex::new(known_peers)),
+        requests: Default::default(),
+        rate_limiter: Mutex::new(RateLimiter::new(RateLimits {
+            requests: Budget {
+                rate: opts.request_rate,
+                burst: opts.request_burst,
+            },
+            gossip: Budget {
+                rate: opts.gossip_rate,
+                burst: opts.gossip_burst,
+            },
+        })),
+        known_transactions: Default::default(),
+        known_blocks: Default::default(),
+        metrics: metrics.clone(),
+        trusted_peers: Default::default(),
+        shutdown: shutdown.clone(),
     });
+    if !opts.trusted_peers.is_empty() {
+        info!("Trusted peers: {:?}", opts.trusted_peers);
+    }
+    capability_server.set_trusted_peers(
+        opts.trusted_peers
+            .iter()
+            .map(|&NR(NodeRecord { addr, id })| (id, addr))
+            .collect(),
+    );
 
     let swarm = Swarm::builder()
         .with_task_group(tasks.clone())
         .with_listen_options(ListenOptions {
             discovery_tasks,
+            priority_discovery_tasks,
             max_peers: opts.max_peers,
             addr: listen_addr.parse().unwrap(),
             cidr: opts.cidr,
             no_new_peers,
         })
-        .with_client_version(format!("sentry/v{}", env!("CARGO_PKG_VERSION")))
+        .with_node_filter(node_filter)
+        .with_client_version(client_version.clone())
         .build(
-            btreemap! {
-                CapabilityId { name: capability_name(), version: protocol_version as CapabilityVersion } => 17,
-            },
+            EthProtocolVersion::ALL
+                .iter()
+                .map(|&version| (version.capability_id(), version.message_space()))
+                .collect(),
             capability_server.clone(),
             secret_key,
         )
@@ -627,21 +1450,188 @@ async fn main() -> anyhow::Result<()> {
 
     info!("RLPx node listening at {}", listen_addr);
 
-    let sentry_addr = opts.sentry_addr.parse()?;
-    tasks.spawn(async move {
-        let svc = SentryServer::new(SentryService::new(capability_server));
+    let mut settings = LiveSettings::new(
+        swarm.clone(),
+        max_peers.clone(),
+        static_peers_sender,
+        log_filter_handle,
+    );
+    settings.apply(reloadable_settings).await?;
+    if let Some(config) = opts.config.clone() {
+        info!("Reloading settings from {} on change", config.display());
+        tasks.spawn(reload_on_change(settings, restart_settings, config));
+    }
+
+    let node_info = NodeInfo {
+        secret_key,
+        client_version,
+        listen_addr: listen_addr.parse()?,
+        discovery_port: (!opts.no_discovery).then(|| opts.discv4_port),
+        discv5_enr: opts.discv5_enr,
+        external_ip: opts.external_ip,
+        discv4_node: discv4_node.clone(),
+    };
+
+    let sentry_addr: SocketAddr = opts.sentry_addr.parse()?;
+    if let Some(peers_file) = opts.peers_file.clone() {
+        let capability_server = capability_server.clone();
+        tasks.spawn(async move {
+            loop {
+                sleep(KNOWN_PEERS_SAVE_INTERVAL).await;
+                if let Err(e) = capability_server.save_known_peers(&peers_file).await {
+                    warn!("Failed to save known peers: {:?}", e);
+                }
+            }
+        });
+    }
+
+    tasks.spawn(keep_trusted_peers_connected(swarm.clone()));
+
+    tasks.spawn({
+        let capability_server = capability_server.clone();
+        async move {
+            loop {
+                sleep(Duration::from_secs(1)).await;
+                capability_server.expire_requests().await;
+            }
+        }
+    });
+
+    if let Some(metrics_addr) = opts.metrics_addr {
+        let capability_server = capability_server.clone();
+        let swarm = swarm.clone();
+        let sample = move |metrics: &Metrics| {
+            metrics.set_peers(
+                capability_server
+                    .all_peer_info()
+                    .into_iter()
+                    .map(|(_, info)| info.connection.direction),
+                swarm.dialing(),
+            );
+            if let Some(node) = &discv4_node {
+                metrics.set_discv4_nodes(node.num_nodes());
+            }
+        };
+        let metrics = metrics.clone();
+        tasks.spawn(async move {
+            info!("Serving metrics at http://{}/metrics", metrics_addr);
+            if let Err(e) = serve_metrics(metrics_addr, metrics, sample).await {
+                warn!("Metrics server failed: {:?}", e);
+            }
+        });
+    }
+
+    let mut server = Server::builder();
+    let tls_config = tls_config(
+        opts.tls_cert.as_deref(),
+        opts.tls_key.as_deref(),
+        opts.tls_client_ca.as_deref(),
+    )
+    .await?;
+    if tls_config.is_none() && opts.auth_token.is_none() && !sentry_addr.ip().is_loopback() {
+        warn!(
+            "Sentry gRPC server on {} is reachable without TLS or token authentication",
+            sentry_addr
+        );
+    }
+    if let Some(tls_config) = tls_config {
+        info!(
+            "Sentry gRPC server uses TLS{}",
+            if opts.tls_client_ca.is_some() {
+                " with client certificate verification"
+            } else {
+                ""
+            }
+        );
+        server = server
+            .tls_config(tls_config)
+            .context("Failed to configure TLS")?;
+    }
+
+    let auth = TokenAuth::new(opts.auth_token.clone());
+    let svc = SentryServer::with_interceptor(
+        SentryService::new(capability_server.clone(), node_info),
+        auth.clone(),
+    );
+    let ext_svc =
+        SentryExtServer::with_interceptor(SentryExtService::new(capability_server.clone()), auth);
+
+    if let Some(admin_addr) = opts.admin_addr {
+        if admin_addr == sentry_addr {
+            bail!(
+                "Admin gRPC server must not share the sentry address {}",
+                sentry_addr
+            );
+        }
+
+        let mut admin_server = Server::builder();
+        let tls_config = tls_config(
+            opts.tls_cert.as_deref(),
+            opts.tls_key.as_deref(),
+            opts.tls_client_ca.as_deref(),
+        )
+        .await?;
+        if tls_config.is_none() && opts.admin_token.is_none() && !admin_addr.ip().is_loopback() {
+            warn!(
+                "Admin gRPC server on {} is reachable without TLS or token authentication",
+                admin_addr
+            );
+        }
+        if let Some(tls_config) = tls_config {
+            admin_server = admin_server
+                .tls_config(tls_config)
+                .context("Failed to configure TLS")?;
+        }
 
+        let admin_svc = AdminServer::with_interceptor(
+            AdminService::new(swarm.clone(), max_peers.clone()),
+            TokenAuth::new(opts.admin_token.clone()),
+        );
+        let shutdown = shutdown.clone();
+        tasks.spawn(async move {
+            info!("Admin gRPC server starting on {}", admin_addr);
+
+            if let Err(e) = admin_server
+                .add_service(admin_svc)
+                .serve_with_shutdown(admin_addr, shutdown.triggered())
+                .await
+            {
+                warn!("Admin gRPC server failed: {:?}", e);
+            }
+        });
+    }
+
+    // Health checks are left unauthenticated so that orchestrators can probe them.
+    let (health_reporter, health_svc) = tonic_health::server::health_reporter();
+    tasks.spawn(report_readiness(
+        health_reporter,
+        swarm.clone(),
+        vec![
+            <SentryServer<SentryService> as NamedService>::NAME,
+            <SentryExtServer<SentryExtService> as NamedService>::NAME,
+        ],
+        opts.min_ready_peers,
+        discovery_expected,
+    ));
+    let (grpc_stopped_sender, grpc_stopped) = tokio::sync::oneshot::channel();
+    tasks.spawn(async move {
         info!("Sentry gRPC server starting on {}", sentry_addr);
 
-        Server::builder()
+        server
+            .layer(GrpcMetricsLayer::new(metrics))
             .initial_connection_window_size(FRAME_SIZE)
             .initial_stream_window_size(FRAME_SIZE)
+            .add_service(health_svc)
             .add_service(svc)
-            .serve(sentry_addr)
+            .add_service(ext_svc)
+            .serve_with_shutdown(sentry_addr, shutdown.triggered())
             .await
             .unwrap();
+        let _ = grpc_stopped_sender.send(());
     });
 
+    let terminated = termination_signal();
+    tokio::pin!(terminated);
     loop {
         info!(
             "Peer info: {} active (+{} dialing) / {} max.",
@@ -650,6 +1640,53 @@ async fn main() -> anyhow::Result<()> {
             opts.max_peers
         );
 
-        sleep(Duration::from_secs(5)).await;
+        let delivery_stats = swarm.delivery_stats();
+        info!(
+            "Outbound messages: {} queued, {} dropped on full queues, {} gossip shed, {} dropped for gone peers, {} skipped for unsupported layouts.",
+            delivery_stats.accepted(),
+            delivery_stats.timed_out(),
+            delivery_stats.shed(),
+            delivery_stats.peer_gone(),
+            delivery_stats.skipped()
+        );
+
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {}
+            res = &mut terminated => {
+                res.context("Failed to listen for termination signals")?;
+                break;
+            }
+        }
+    }
+
+    info!("Shutting down");
+    swarm.stop();
+    // Remember peers while they are still connected.
+    capability_server.refresh_known_peers();
+
+    capability_server
+        .disconnect_all(DisconnectReason::ClientQuitting)
+        .await;
+    let deadline = Instant::now() + SHUTDOWN_GRACE_PERIOD;
+    while !capability_server.all_peers().is_empty() && Instant::now() < deadline {
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let _ = shutdown_sender.send(true);
+    if tokio::time::timeout(GRPC_SHUTDOWN_TIMEOUT, grpc_stopped)
+        .await
+        .is_err()
+    {
+        warn!("gRPC calls did not finish in time, closing them");
+    }
+
+    if let Some(peers_file) = &opts.peers_file {
+        capability_server
+            .save_known_peers(peers_file)
+            .await
+            .context("Failed to save known peers")?;
     }
+    info!("Shutdown complete");
+
+    Ok(())
 }
@@ -0,0 +1,433 @@
+use anyhow::bail;
+use ethereum_interfaces::sentry::InboundMessage;
+use ethereum_types::H256;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::{futures::Notified, Notify};
+
+/// How exclusive messages are spread across members of a consumer group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Balancing {
+    /// All messages from a peer go to the same member while membership does not change.
+    PeerAffinity,
+    RoundRobin,
+}
+
+#[derive(Debug)]
+struct Member {
+    name: Arc<str>,
+    /// Message IDs the member subscribed to, all if empty.
+    ids: HashSet<i32>,
+}
+
+impl Member {
+    fn wants(&self, id: i32) -> bool {
+        self.ids.is_empty() || self.ids.contains(&id)
+    }
+}
+
+#[derive(Debug)]
+struct ConsumerGroup {
+    balancing: Balancing,
+    /// Connected members, sorted by name so that peer affinity does not depend on join order.
+    members: Vec<Member>,
+    next: usize,
+}
+
+impl ConsumerGroup {
+    /// Member to deliver the message to, out of the ones subscribed to its ID.
+    fn assign(&mut self, message: &InboundMessage) -> Option<Arc<str>> {
+        let candidates = self
+            .members
+            .iter()
+            .filter(|member| member.wants(message.id))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let index = match self.balancing {
+            Balancing::PeerAffinity => {
+                let peer = message.peer_id.clone().map(H256::from).unwrap_or_default();
+                (peer.to_low_u64_be() % candidates.len() as u64) as usize
+            }
+            Balancing::RoundRobin => {
+                self.next = self.next.wrapping_add(1);
+                self.next % candidates.len()
+            }
+        };
+
+        Some(candidates[index].name.clone())
+    }
+}
+
+/// Membership of a subscriber in a consumer group.
+#[derive(Clone, Debug)]
+pub struct Consumer {
+    pub group: Arc<str>,
+    pub name: Arc<str>,
+}
+
+#[derive(Debug)]
+pub struct SequencedMessage {
+    pub sequence: u64,
+    pub message: InboundMessage,
+    /// Exclusive messages go to one member of every consumer group, others go to every subscriber.
+    exclusive: bool,
+    /// Member each consumer group got an exclusive message assigned to.
+    assignees: Vec<(Arc<str>, Arc<str>)>,
+}
+
+impl SequencedMessage {
+    /// Whether the message should be delivered to the subscriber.
+    pub fn is_for(&self, consumer: Option<&Consumer>) -> bool {
+        match consumer {
+            Some(consumer) if self.exclusive => self
+                .assignees
+                .iter()
+                .any(|(group, member)| *group == consumer.group && *member == consumer.name),
+            _ => true,
+        }
+    }
+}
+
+/// Result of reading the log from some position.
+#[derive(Debug, Default)]
+pub struct LogRead {
+    /// Messages that fell out of the replay buffer before they could be read.
+    pub dropped: u64,
+    pub messages: Vec<Arc<SequencedMessage>>,
+}
+
+#[derive(Debug)]
+struct ReplayBuffer {
+    messages: VecDeque<Arc<SequencedMessage>>,
+    capacity: usize,
+    /// Sequence number of the latest message, 0 if there were none yet.
+    latest: u64,
+    groups: HashMap<Arc<str>, ConsumerGroup>,
+}
+
+/// Inbound messages numbered in order of arrival. The latest ones are kept for subscribers to catch up or resume from.
+#[derive(Debug)]
+pub struct MessageLog {
+    /// Random and non-zero, tells sequence numbers from other runs of the sentry apart.
+    epoch: u64,
+    buffer: Mutex<ReplayBuffer>,
+    new_messages: Notify,
+    subscribers: AtomicUsize,
+    consumer_names: AtomicU64,
+}
+
+impl MessageLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            epoch: secp256k1::rand::random::<u64>().max(1),
+            buffer: Mutex::new(ReplayBuffer {
+                messages: VecDeque::with_capacity(capacity),
+                capacity,
+                latest: 0,
+                groups: HashMap::new(),
+            }),
+            new_messages: Notify::new(),
+            subscribers: AtomicUsize::new(0),
+            consumer_names: AtomicU64::new(0),
+        }
+    }
+
+    pub fn push(&self, message: InboundMessage, exclusive: bool) -> u64 {
+        let sequence = {
+            let mut buffer = self.buffer.lock();
+            buffer.latest += 1;
+            let sequence = buffer.latest;
+            if buffer.messages.len() >= buffer.capacity {
+                buffer.messages.pop_front();
+            }
+            let assignees = if exclusive {
+                buffer
+                    .groups
+                    .iter_mut()
+                    .filter_map(|(name, group)| Some((name.clone(), group.assign(&message)?)))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            buffer.messages.push_back(Arc::new(SequencedMessage {
+                sequence,
+                message,
+                exclusive,
+                assignees,
+            }));
+            sequence
+        };
+        self.new_messages.notify_waiters();
+
+        sequence
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn latest(&self) -> u64 {
+        self.buffer.lock().latest
+    }
+
+    /// Up to `limit` messages following `after`.
+    pub fn read_after(&self, after: u64, limit: usize) -> LogRead {
+        let buffer = self.buffer.lock();
+        let oldest = if let Some(message) = buffer.messages.front() {
+            message.sequence
+        } else {
+            return LogRead {
+                dropped: buffer.latest.saturating_sub(after),
+                messages: Vec::new(),
+            };
+        };
+
+        let dropped = oldest.saturating_sub(after + 1);
+        let skip = after.saturating_sub(oldest - 1) as usize;
+
+        LogRead {
+            dropped,
+            messages: buffer
+                .messages
+                .iter()
+                .skip(skip)
+                .take(limit)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Resolves once a message is pushed after this call. Create it before reading to not miss any.
+    pub fn new_message(&self) -> Notified<'_> {
+        self.new_messages.notified()
+    }
+
+    pub fn subscribe(self: &Arc<Self>) -> Subscription {
+        self.subscribers.fetch_add(1, Ordering::SeqCst);
+        Subscription {
+            log: self.clone(),
+            consumer: None,
+        }
+    }
+
+    /// Subscribe as a member of consumer group. Group's balancing is set by its first member.
+    /// Exclusive messages are only assigned to members subscribed to their ID, all IDs if `ids` is empty.
+    ///
+    /// Rejoining under the same name picks up messages assigned to the member before it left.
+    pub fn join(
+        self: &Arc<Self>,
+        group: &str,
+        name: Option<&str>,
+        balancing: Balancing,
+        ids: HashSet<i32>,
+    ) -> anyhow::Result<Subscription> {
+        let name: Arc<str> = match name {
+            Some(name) => name.into(),
+            None => format!(
+                "consumer-{}",
+                self.consumer_names.fetch_add(1, Ordering::Relaxed)
+            )
+            .into(),
+        };
+
+        let mut buffer = self.buffer.lock();
+        let group: Arc<str> = buffer
+            .groups
+            .get_key_value(group)
+            .map_or_else(|| group.into(), |(group, _)| group.clone());
+        let consumer_group = buffer.groups.entry(group.clone()).or_insert(ConsumerGroup {
+            balancing,
+            members: Vec::new(),
+            next: 0,
+        });
+        if consumer_group.balancing != balancing {
+            bail!(
+                "group {} uses {:?} balancing",
+                group,
+                consumer_group.balancing
+            );
+        }
+        match consumer_group
+            .members
+            .binary_search_by(|member| member.name.cmp(&name))
+        {
+            Ok(_) => bail!("consumer {} is already connected to group {}", name, group),
+            Err(index) => consumer_group.members.insert(
+                index,
+                Member {
+                    name: name.clone(),
+                    ids,
+                },
+            ),
+        }
+        drop(buffer);
+
+        let mut subscription = self.subscribe();
+        subscription.consumer = Some(Consumer { group, name });
+        Ok(subscription)
+    }
+
+    fn leave(&self, consumer: &Consumer) {
+        let mut buffer = self.buffer.lock();
+        if let Some(group) = buffer.groups.get_mut(&consumer.group) {
+            group.members.retain(|member| member.name != consumer.name);
+            if group.members.is_empty() {
+                buffer.groups.remove(&consumer.group);
+            }
+        }
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        self.subscribers.load(Ordering::SeqCst) > 0
+    }
+}
+
+/// Keeps the subscriber counted, and a member of its consumer group, while alive.
+#[derive(Debug)]
+pub struct Subscription {
+    log: Arc<MessageLog>,
+    consumer: Option<Consumer>,
+}
+
+impl Subscription {
+    pub fn log(&self) -> &MessageLog {
+        &self.log
+    }
+
+    pub fn consumer(&self) -> Option<&Consumer> {
+        self.consumer.as_ref()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(consumer) = &self.consumer {
+            self.log.leave(consumer);
+        }
+        self.log.subscribers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: i32) -> InboundMessage {
+        InboundMessage {
+            id,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn replay() {
+        let log = MessageLog::new(3);
+        for id in 1..=5 {
+            assert_eq!(log.push(message(id), false), id as u64);
+        }
+
+        let read = log.read_after(3, 10);
+        assert_eq!(read.dropped, 0);
+        assert_eq!(
+            read.messages.iter().map(|m| m.sequence).collect::<Vec<_>>(),
+            vec![4, 5]
+        );
+
+        let read = log.read_after(0, 2);
+        assert_eq!(read.dropped, 2);
+        assert_eq!(
+            read.messages.iter().map(|m| m.sequence).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+
+        let read = log.read_after(5, 10);
+        assert_eq!(read.dropped, 0);
+        assert!(read.messages.is_empty());
+    }
+
+    #[test]
+    fn consumer_groups() {
+        let log = Arc::new(MessageLog::new(16));
+        let plain = log.subscribe();
+        let a = log
+            .join("core", Some("a"), Balancing::RoundRobin, HashSet::new())
+            .unwrap();
+        let b = log
+            .join("core", Some("b"), Balancing::RoundRobin, HashSet::new())
+            .unwrap();
+        assert!(log
+            .join("core", Some("a"), Balancing::RoundRobin, HashSet::new())
+            .is_err());
+        assert!(log
+            .join("core", Some("c"), Balancing::PeerAffinity, HashSet::new())
+            .is_err());
+
+        log.push(message(1), false);
+        for id in 2..=5 {
+            log.push(message(id), true);
+        }
+
+        let read = log.read_after(0, 16);
+        let received = |subscription: &Subscription| {
+            read.messages
+                .iter()
+                .filter(|m| m.is_for(subscription.consumer()))
+                .map(|m| m.message.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(received(&plain), vec![1, 2, 3, 4, 5]);
+        assert_eq!(received(&a), vec![1, 3, 5]);
+        assert_eq!(received(&b), vec![1, 2, 4]);
+
+        drop(a);
+        drop(b);
+        assert!(log
+            .join("core", None, Balancing::PeerAffinity, HashSet::new())
+            .is_ok());
+    }
+
+    #[test]
+    fn consumer_group_filters() {
+        let log = Arc::new(MessageLog::new(16));
+        let headers = [1].into_iter().collect::<HashSet<_>>();
+        let bodies = [2].into_iter().collect::<HashSet<_>>();
+        let a = log
+            .join("core", Some("a"), Balancing::RoundRobin, headers)
+            .unwrap();
+        let b = log
+            .join("core", Some("b"), Balancing::RoundRobin, bodies)
+            .unwrap();
+
+        for id in [1, 1, 2, 2, 3] {
+            log.push(message(id), true);
+        }
+
+        let read = log.read_after(0, 16);
+        let received = |subscription: &Subscription| {
+            read.messages
+                .iter()
+                .filter(|m| m.is_for(subscription.consumer()))
+                .map(|m| m.message.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(received(&a), vec![1, 1]);
+        assert_eq!(received(&b), vec![2, 2]);
+        // Nobody in the group wants it.
+        assert!(read.messages[4].assignees.is_empty());
+    }
+    #[test]
+    fn epochs() {
+        let (a, b) = (MessageLog::new(1), MessageLog::new(1));
+        assert_ne!(a.epoch(), 0);
+        assert_ne!(a.epoch(), b.epoch());
+    }
+}
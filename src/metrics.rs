@@ -0,0 +1,388 @@
+use crate::eth::EthMessageId;
+use devp2p::{ConnectionDirection, DisconnectReason, Discovery, Message, OutboundEvent};
+use futures::{future::BoxFuture, StreamExt};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, StatusCode,
+};
+use num_traits::FromPrimitive;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+
+/// gRPC status code of calls to methods that are not served.
+const GRPC_UNIMPLEMENTED: &str = "12";
+
+/// Result of the eth status handshake with a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Handshake {
+    Accepted,
+    /// We had no status to send or validate against.
+    NotReady,
+    MalformedStatus,
+    IncompatibleFork,
+}
+
+impl Handshake {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Accepted => "accepted",
+            Self::NotReady => "not_ready",
+            Self::MalformedStatus => "malformed_status",
+            Self::IncompatibleFork => "incompatible_fork",
+        }
+    }
+}
+
+fn message_label(id: usize) -> String {
+    EthMessageId::from_usize(id).map_or_else(|| "unknown".to_string(), |id| format!("{:?}", id))
+}
+
+/// Metrics of the sentry, exported in the Prometheus text format.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    peers: IntGaugeVec,
+    dialing: IntGauge,
+    handshakes: IntCounterVec,
+    disconnects: IntCounterVec,
+    inbound_messages: IntCounterVec,
+    inbound_bytes: IntCounterVec,
+    outbound_messages: IntCounterVec,
+    outbound_bytes: IntCounterVec,
+    broadcast_lags: IntCounterVec,
+    broadcast_dropped: IntCounterVec,
+    discovered_records: IntCounterVec,
+    discv4_nodes: IntGauge,
+    grpc_latency: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new_custom(Some("sentry".into()), None)?;
+
+        let peers = IntGaugeVec::new(
+            Opts::new("peers", "Connected peers by connection direction"),
+            &["direction"],
+        )?;
+        let dialing = IntGauge::new("dialing_peers", "Peers being dialed")?;
+        let handshakes = IntCounterVec::new(
+            Opts::new("handshakes_total", "Status handshakes by outcome"),
+            &["outcome"],
+        )?;
+        let disconnects = IntCounterVec::new(
+            Opts::new("disconnects_total", "Peer disconnects by reason"),
+            &["reason"],
+        )?;
+        let inbound_messages = IntCounterVec::new(
+            Opts::new("inbound_messages_total", "Messages received from peers"),
+            &["message"],
+        )?;
+        let inbound_bytes = IntCounterVec::new(
+            Opts::new("inbound_bytes_total", "Payload bytes received from peers"),
+            &["message"],
+        )?;
+        let outbound_messages = IntCounterVec::new(
+            Opts::new("outbound_messages_total", "Messages sent to peers"),
+            &["message"],
+        )?;
+        let outbound_bytes = IntCounterVec::new(
+            Opts::new("outbound_bytes_total", "Payload bytes sent to peers"),
+            &["message"],
+        )?;
+        let broadcast_lags = IntCounterVec::new(
+            Opts::new(
+                "broadcast_lags_total",
+                "Times a subscriber fell behind a broadcast stream",
+            ),
+            &["stream"],
+        )?;
+        let broadcast_dropped = IntCounterVec::new(
+            Opts::new(
+                "broadcast_dropped_total",
+                "Events lagging subscribers missed",
+            ),
+            &["stream"],
+        )?;
+        let discovered_records = IntCounterVec::new(
+            Opts::new(
+                "discovered_records_total",
+                "Node records found by discovery",
+            ),
+            &["source"],
+        )?;
+        let discv4_nodes = IntGauge::new("discv4_nodes", "Nodes in the discv4 table")?;
+        let grpc_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "grpc_call_duration_seconds",
+                "Time until gRPC calls are answered, or until streaming calls are accepted",
+            ),
+            &["method"],
+        )?;
+
+        registry.register(Box::new(peers.clone()))?;
+        registry.register(Box::new(dialing.clone()))?;
+        registry.register(Box::new(handshakes.clone()))?;
+        registry.register(Box::new(disconnects.clone()))?;
+        registry.register(Box::new(inbound_messages.clone()))?;
+        registry.register(Box::new(inbound_bytes.clone()))?;
+        registry.register(Box::new(outbound_messages.clone()))?;
+        registry.register(Box::new(outbound_bytes.clone()))?;
+        registry.register(Box::new(broadcast_lags.clone()))?;
+        registry.register(Box::new(broadcast_dropped.clone()))?;
+        registry.register(Box::new(discovered_records.clone()))?;
+        registry.register(Box::new(discv4_nodes.clone()))?;
+        registry.register(Box::new(grpc_latency.clone()))?;
+
+        Ok(Self {
+            registry,
+            peers,
+            dialing,
+            handshakes,
+            disconnects,
+            inbound_messages,
+            inbound_bytes,
+            outbound_messages,
+            outbound_bytes,
+            broadcast_lags,
+            broadcast_dropped,
+            discovered_records,
+            discv4_nodes,
+            grpc_latency,
+        })
+    }
+
+    pub fn set_peers(
+        &self,
+        directions: impl IntoIterator<Item = ConnectionDirection>,
+        dialing: usize,
+    ) {
+        let (mut inbound, mut outbound) = (0, 0);
+        for direction in directions {
+            match direction {
+                ConnectionDirection::Inbound => inbound += 1,
+                ConnectionDirection::Outbound => outbound += 1,
+            }
+        }
+        self.peers.with_label_values(&["inbound"]).set(inbound);
+        self.peers.with_label_values(&["outbound"]).set(outbound);
+        self.dialing.set(dialing as i64);
+    }
+
+    pub fn set_discv4_nodes(&self, nodes: usize) {
+        self.discv4_nodes.set(nodes as i64);
+    }
+
+    pub fn on_handshake(&self, outcome: Handshake) {
+        self.handshakes.with_label_values(&[outcome.label()]).inc();
+    }
+
+    pub fn on_disconnect(&self, reason: Option<DisconnectReason>) {
+        let reason = reason.map_or_else(|| "unknown".to_string(), |reason| format!("{:?}", reason));
+        self.disconnects.with_label_values(&[&reason]).inc();
+    }
+
+    pub fn on_inbound(&self, message: &Message) {
+        let label = message_label(message.id);
+        self.inbound_messages.with_label_values(&[&label]).inc();
+        self.inbound_bytes
+            .with_label_values(&[&label])
+            .inc_by(message.data.len() as u64);
+    }
+
+    pub fn on_outbound(&self, event: &OutboundEvent) {
+        if let OutboundEvent::Message { message, .. } = event {
+            let label = message_label(message.id);
+            self.outbound_messages.with_label_values(&[&label]).inc();
+            self.outbound_bytes
+                .with_label_values(&[&label])
+                .inc_by(message.data.len() as u64);
+        }
+    }
+
+    /// Subscriber of `stream` missed `dropped` events.
+    pub fn on_lag(&self, stream: &str, dropped: u64) {
+        self.broadcast_lags.with_label_values(&[stream]).inc();
+        self.broadcast_dropped
+            .with_label_values(&[stream])
+            .inc_by(dropped);
+    }
+
+    /// Count records found by the discovery as coming from `source`.
+    pub fn count_discoveries(&self, source: &str, discovery: Discovery) -> Discovery {
+        let counter = self.discovered_records.with_label_values(&[source]);
+        Box::pin(discovery.inspect(move |record| {
+            if record.is_ok() {
+                counter.inc();
+            }
+        }))
+    }
+
+    pub fn observe_grpc_call(&self, method: &str, duration: Duration) {
+        self.grpc_latency
+            .with_label_values(&[method])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn encode(&self) -> prometheus::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Records latencies of gRPC calls by method.
+#[derive(Clone, Debug)]
+pub struct GrpcMetricsLayer {
+    metrics: Arc<Metrics>,
+}
+
+impl GrpcMetricsLayer {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> Layer<S> for GrpcMetricsLayer {
+    type Service = GrpcMetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcMetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GrpcMetricsService<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for GrpcMetricsService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let method = request.uri().path().to_string();
+        let metrics = self.metrics.clone();
+        let started_at = Instant::now();
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await;
+            if let Ok(response) = &response {
+                // Keep arbitrary paths sent by clients out of the label set.
+                let unimplemented = response
+                    .headers()
+                    .get("grpc-status")
+                    .map_or(false, |status| status == GRPC_UNIMPLEMENTED);
+                let method = if unimplemented { "unknown" } else { &method };
+                metrics.observe_grpc_call(method, started_at.elapsed());
+            }
+            response
+        })
+    }
+}
+
+fn respond(metrics: &Metrics) -> Response<Body> {
+    match metrics.encode() {
+        Ok(buf) => Response::builder()
+            .header(CONTENT_TYPE, TextEncoder::new().format_type())
+            .body(Body::from(buf))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e.to_string()))
+            .unwrap(),
+    }
+}
+
+/// Serve metrics at `/metrics`. `sample` refreshes gauges right before every scrape.
+pub async fn serve_metrics(
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    sample: impl Fn(&Metrics) + Send + Sync + 'static,
+) -> anyhow::Result<()> {
+    let sample = Arc::new(sample);
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        let sample = sample.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let response = if request.uri().path() == "/metrics" {
+                    sample(&metrics);
+                    respond(&metrics)
+                } else {
+                    Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::empty())
+                        .unwrap()
+                };
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    hyper::Server::try_bind(&addr)?.serve(make_service).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_traits::ToPrimitive;
+
+    #[test]
+    fn export() {
+        let metrics = Metrics::new().unwrap();
+        metrics.on_inbound(&Message {
+            id: EthMessageId::BlockHeaders.to_usize().unwrap(),
+            data: vec![0; 10].into(),
+        });
+        metrics.on_inbound(&Message {
+            id: 0xff,
+            data: vec![0; 3].into(),
+        });
+        metrics.on_disconnect(Some(DisconnectReason::UselessPeer));
+        metrics.set_peers(
+            vec![
+                ConnectionDirection::Inbound,
+                ConnectionDirection::Outbound,
+                ConnectionDirection::Outbound,
+            ],
+            4,
+        );
+
+        let text = String::from_utf8(metrics.encode().unwrap()).unwrap();
+        for line in [
+            "sentry_inbound_bytes_total{message=\"BlockHeaders\"} 10",
+            "sentry_inbound_messages_total{message=\"unknown\"} 1",
+            "sentry_disconnects_total{reason=\"UselessPeer\"} 1",
+            "sentry_peers{direction=\"outbound\"} 2",
+            "sentry_dialing_peers 4",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} missing", line);
+        }
+    }
+}
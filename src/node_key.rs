@@ -0,0 +1,229 @@
+use anyhow::{bail, Context};
+use secp256k1::SecretKey;
+use std::{io::ErrorKind, path::Path};
+use tracing::*;
+
+/// Parse a key in geth's `nodekey` format: the secret as 64 hex characters.
+pub fn parse_node_key(data: &str) -> anyhow::Result<SecretKey> {
+    let data = data.trim();
+    if data.len() != 64 {
+        bail!("expected 64 hex characters, got {}", data.len());
+    }
+
+    Ok(SecretKey::from_slice(&hex::decode(data)?)?)
+}
+
+/// Format the key like geth's `nodekey` file, so that it can be moved between geth and the sentry.
+pub fn format_node_key(key: &SecretKey) -> String {
+    hex::encode(&key[..])
+}
+
+#[cfg(unix)]
+fn check_permissions(metadata: &std::fs::Metadata) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = metadata.permissions().mode();
+    if mode & 0o004 != 0 {
+        bail!(
+            "node key file is world-readable (mode {:o}), restrict it with chmod 600",
+            mode & 0o777
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_: &std::fs::Metadata) -> anyhow::Result<()> {
+    Ok(())
+}
+
+async fn write_node_key(path: &Path, key: &SecretKey) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    // Write to a temporary file first so that a crash never leaves a truncated key behind.
+    let tmp_path = path.with_extension("tmp");
+    match tokio::fs::remove_file(&tmp_path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("failed to remove {}", tmp_path.display()))
+        }
+        _ => {}
+    }
+    let mut file = options
+        .open(&tmp_path)
+        .await
+        .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, format_node_key(key).as_bytes())
+        .await
+        .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .with_context(|| format!("failed to replace {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Load the node key from the file, or create the file on first run, with `key` if given
+/// and a new key otherwise.
+pub async fn load_or_create_node_key(
+    path: &Path,
+    key: Option<SecretKey>,
+) -> anyhow::Result<SecretKey> {
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) => {
+            check_permissions(&metadata)
+                .with_context(|| format!("refusing to use {}", path.display()))?;
+            let data = tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            let loaded = parse_node_key(&data)
+                .with_context(|| format!("invalid node key in {}", path.display()))?;
+            if key.map_or(false, |key| key != loaded) {
+                bail!("node key differs from the one in {}", path.display());
+            }
+
+            info!("Loaded node key from {}", path.display());
+            Ok(loaded)
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let key = match key {
+                Some(key) => {
+                    info!("Saving node key to {}", path.display());
+                    key
+                }
+                None => {
+                    info!("Generating new node key in {}", path.display());
+                    SecretKey::new(&mut secp256k1::rand::thread_rng())
+                }
+            };
+            write_node_key(path, &key).await?;
+            Ok(key)
+        }
+        Err(e) => Err(e).with_context(|| format!("failed to access {}", path.display())),
+    }
+}
+
+/// Replace the key in `path`, if any, with the one from geth's `nodekey` file at `source`.
+pub async fn import_node_key(source: &Path, path: &Path) -> anyhow::Result<SecretKey> {
+    let data = tokio::fs::read_to_string(source)
+        .await
+        .with_context(|| format!("failed to read {}", source.display()))?;
+    let key = parse_node_key(&data)
+        .with_context(|| format!("invalid node key in {}", source.display()))?;
+    write_node_key(path, &key).await?;
+
+    info!(
+        "Imported node key from {} to {}",
+        source.display(),
+        path.display()
+    );
+    Ok(key)
+}
+
+/// Write the key to a new file in geth's `nodekey` format, refusing to overwrite an existing one.
+pub async fn export_node_key(path: &Path, key: &SecretKey) -> anyhow::Result<()> {
+    match tokio::fs::metadata(path).await {
+        Ok(_) => bail!("{} already exists", path.display()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("failed to access {}", path.display())),
+    }
+    write_node_key(path, key).await?;
+
+    info!("Exported node key to {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("sentry-node-key-{}", rand::random::<u64>()))
+    }
+
+    #[tokio::test]
+    async fn node_key_file() {
+        let dir = temp_dir();
+        // Created on first run along with the key.
+        let path = dir.join("keys").join("nodekey");
+
+        let key = load_or_create_node_key(&path, None).await.unwrap();
+        assert_eq!(load_or_create_node_key(&path, None).await.unwrap(), key);
+        assert_eq!(
+            load_or_create_node_key(&path, Some(key)).await.unwrap(),
+            key
+        );
+        let other = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        assert!(load_or_create_node_key(&path, Some(other)).await.is_err());
+
+        // Same as geth, which writes no trailing newline but accepts one.
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(data, format_node_key(&key));
+        assert_eq!(parse_node_key(&format!("{}\n", data)).unwrap(), key);
+        assert!(parse_node_key(&data[1..]).is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let metadata = std::fs::metadata(&path).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(load_or_create_node_key(&path, None).await.is_err());
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn import() {
+        let dir = temp_dir();
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("nodekey");
+        let geth_path = dir.join("geth-nodekey");
+
+        let old = load_or_create_node_key(&path, None).await.unwrap();
+        let key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        std::fs::write(&geth_path, format!("{}\n", format_node_key(&key))).unwrap();
+        assert_ne!(old, key);
+
+        assert_eq!(import_node_key(&geth_path, &path).await.unwrap(), key);
+        assert_eq!(load_or_create_node_key(&path, None).await.unwrap(), key);
+
+        std::fs::write(&geth_path, "not a key").unwrap();
+        assert!(import_node_key(&geth_path, &path).await.is_err());
+        assert_eq!(load_or_create_node_key(&path, None).await.unwrap(), key);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn export() {
+        let dir = temp_dir();
+        let path = dir.join("nodekey");
+        let key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+
+        export_node_key(&path, &key).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format_node_key(&key)
+        );
+        assert_eq!(load_or_create_node_key(&path, None).await.unwrap(), key);
+
+        let other = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        assert!(export_node_key(&path, &other).await.is_err());
+        assert_eq!(load_or_create_node_key(&path, None).await.unwrap(), key);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+use crate::eth::EthMessageId;
+use devp2p::{Message, OutboundEvent};
+use num_traits::FromPrimitive;
+use parking_lot::Mutex;
+use std::{collections::VecDeque, time::Duration};
+use tokio::{
+    sync::Notify,
+    time::{timeout_at, Instant},
+};
+
+/// Order in which queued events are sent to the peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Disconnects and status. Never held back by a full queue.
+    Control,
+    Response,
+    Request,
+    /// Announcements. Oldest ones are shed to make room for anything else.
+    Gossip,
+}
+
+impl Priority {
+    const ALL: [Self; 4] = [Self::Control, Self::Response, Self::Request, Self::Gossip];
+
+    pub fn of(event: &OutboundEvent) -> Self {
+        let id = match event {
+            OutboundEvent::Disconnect { .. } => return Self::Control,
+            OutboundEvent::Message {
+                message: Message { id, .. },
+                ..
+            } => EthMessageId::from_usize(*id),
+        };
+
+        match id {
+            Some(EthMessageId::Status) => Self::Control,
+            Some(id) if id.is_response() => Self::Response,
+            Some(id) if id.response_id().is_some() => Self::Request,
+            _ => Self::Gossip,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    /// Queue is full of events that cannot be shed.
+    Full(OutboundEvent),
+    /// Peer is gone.
+    Closed(OutboundEvent),
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    queues: [VecDeque<OutboundEvent>; 4],
+    closed: bool,
+}
+
+impl QueueState {
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+}
+
+/// Bounded per-peer queue of outbound events, sent in order of priority.
+#[derive(Debug)]
+pub struct OutboundQueue {
+    state: Mutex<QueueState>,
+    capacity: usize,
+    items: Notify,
+    space: Notify,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Default::default(),
+            capacity,
+            items: Notify::new(),
+            space: Notify::new(),
+        }
+    }
+
+    /// Queue the event if there is room for it. Returns the older gossip event shed to make room, if any.
+    pub fn try_push(&self, event: OutboundEvent) -> Result<Option<OutboundEvent>, PushError> {
+        let priority = Priority::of(&event);
+        let mut state = self.state.lock();
+        if state.closed {
+            return Err(PushError::Closed(event));
+        }
+
+        let mut shed = None;
+        if state.len() >= self.capacity && priority != Priority::Control {
+            shed = state.queues[Priority::Gossip as usize].pop_front();
+            if shed.is_none() {
+                return Err(PushError::Full(event));
+            }
+        }
+        state.queues[priority as usize].push_back(event);
+        drop(state);
+
+        self.items.notify_one();
+        Ok(shed)
+    }
+
+    /// Queue the event, waiting up to `timeout` for room unless it is gossip.
+    pub async fn push_timeout(
+        &self,
+        mut event: OutboundEvent,
+        timeout: Duration,
+    ) -> Result<Option<OutboundEvent>, PushError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            event = match self.try_push(event) {
+                Err(PushError::Full(event)) if Priority::of(&event) != Priority::Gossip => event,
+                res => return res,
+            };
+
+            if timeout_at(deadline, self.space.notified()).await.is_err() {
+                return Err(PushError::Full(event));
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<OutboundEvent> {
+        let event = {
+            let mut state = self.state.lock();
+            Priority::ALL
+                .iter()
+                .find_map(|&priority| state.queues[priority as usize].pop_front())
+        };
+        if event.is_some() {
+            self.space.notify_one();
+        }
+
+        event
+    }
+
+    /// Next event to send, `None` once the queue is closed and drained.
+    pub async fn recv(&self) -> Option<OutboundEvent> {
+        loop {
+            if let Some(event) = self.pop() {
+                return Some(event);
+            }
+            if self.state.lock().closed {
+                return None;
+            }
+
+            self.items.notified().await;
+        }
+    }
+
+    pub fn close(&self) {
+        self.state.lock().closed = true;
+        self.items.notify_one();
+        self.space.notify_waiters();
+    }
+
+    pub fn depth(&self) -> usize {
+        self.state.lock().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eth::capability_name;
+    use devp2p::DisconnectReason;
+    use num_traits::ToPrimitive;
+
+    fn message(id: EthMessageId) -> OutboundEvent {
+        OutboundEvent::Message {
+            capability_name: capability_name(),
+            message: Message {
+                id: id.to_usize().unwrap(),
+                data: Default::default(),
+            },
+        }
+    }
+
+    fn popped_priorities(queue: &OutboundQueue) -> Vec<Priority> {
+        std::iter::from_fn(|| queue.pop())
+            .map(|event| Priority::of(&event))
+            .collect()
+    }
+
+    #[test]
+    fn priorities_and_shedding() {
+        let queue = OutboundQueue::new(3);
+        assert!(queue
+            .try_push(message(EthMessageId::Transactions))
+            .unwrap()
+            .is_none());
+        assert!(queue
+            .try_push(message(EthMessageId::NewBlockHashes))
+            .unwrap()
+            .is_none());
+        assert!(queue
+            .try_push(message(EthMessageId::GetBlockHeaders))
+            .unwrap()
+            .is_none());
+
+        // Full queue sheds the oldest gossip for anything but control events, which always fit.
+        assert!(matches!(
+            queue.try_push(message(EthMessageId::BlockHeaders)).unwrap(),
+            Some(OutboundEvent::Message {
+                message: Message { id, .. },
+                ..
+            }) if id == EthMessageId::Transactions.to_usize().unwrap()
+        ));
+        assert!(queue
+            .try_push(OutboundEvent::Disconnect {
+                reason: DisconnectReason::UselessPeer
+            })
+            .unwrap()
+            .is_none());
+        assert_eq!(queue.depth(), 4);
+
+        assert_eq!(
+            popped_priorities(&queue),
+            vec![
+                Priority::Control,
+                Priority::Response,
+                Priority::Request,
+                Priority::Gossip
+            ]
+        );
+
+        let queue = OutboundQueue::new(1);
+        queue.try_push(message(EthMessageId::GetReceipts)).unwrap();
+        assert!(matches!(
+            queue.try_push(message(EthMessageId::Transactions)),
+            Err(PushError::Full(_))
+        ));
+        queue.close();
+        assert!(matches!(
+            queue.try_push(message(EthMessageId::Transactions)),
+            Err(PushError::Closed(_))
+        ));
+    }
+}
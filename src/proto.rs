@@ -0,0 +1,9 @@
+//! gRPC APIs served in addition to `ethereum_interfaces::sentry`.
+
+pub mod sentry_ext {
+    tonic::include_proto!("sentry_ext");
+}
+
+pub mod admin {
+    tonic::include_proto!("admin");
+}
@@ -0,0 +1,184 @@
+use crate::eth::EthMessageId;
+use devp2p::PeerIdHash;
+use std::{collections::HashMap, time::Instant};
+
+/// Number of messages a peer may have dropped for exceeding its budgets before it gets disconnected.
+const DROP_ALLOWANCE: Budget = Budget {
+    rate: 1.0,
+    burst: 100.0,
+};
+
+/// Sustained messages per second and the number of messages that may arrive at once.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Budget {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+/// Budgets applied to each peer separately for every message type.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimits {
+    pub requests: Budget,
+    pub gossip: Budget,
+}
+
+impl RateLimits {
+    fn budget(&self, id: EthMessageId) -> Option<Budget> {
+        if id.response_id().is_some() {
+            Some(self.requests)
+        } else if id.is_gossip() {
+            Some(self.gossip)
+        } else {
+            // Responses are matched against our requests instead.
+            None
+        }
+    }
+}
+
+/// What to do with an inbound message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Accept,
+    /// Peer exceeded its budget for this message type.
+    Drop,
+    /// Peer keeps exceeding its budgets.
+    Disconnect,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn full(budget: Budget, now: Instant) -> Self {
+        Self {
+            tokens: budget.burst,
+            updated_at: now,
+        }
+    }
+
+    fn try_take(&mut self, budget: Budget, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.rate).min(budget.burst);
+        self.updated_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PeerBuckets {
+    messages: HashMap<EthMessageId, TokenBucket>,
+    drops: TokenBucket,
+}
+
+/// Token buckets limiting inbound requests and gossip of every peer.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    peers: HashMap<PeerIdHash, PeerBuckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn admit(&mut self, peer: PeerIdHash, id: EthMessageId, now: Instant) -> Admission {
+        let budget = if let Some(budget) = self.limits.budget(id) {
+            budget
+        } else {
+            return Admission::Accept;
+        };
+
+        let buckets = self.peers.entry(peer).or_insert_with(|| PeerBuckets {
+            messages: HashMap::new(),
+            drops: TokenBucket::full(DROP_ALLOWANCE, now),
+        });
+        if buckets
+            .messages
+            .entry(id)
+            .or_insert_with(|| TokenBucket::full(budget, now))
+            .try_take(budget, now)
+        {
+            Admission::Accept
+        } else if buckets.drops.try_take(DROP_ALLOWANCE, now) {
+            Admission::Drop
+        } else {
+            Admission::Disconnect
+        }
+    }
+
+    pub fn remove_peer(&mut self, peer: PeerIdHash) {
+        self.peers.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn budgets() {
+        let limits = RateLimits {
+            requests: Budget {
+                rate: 1.0,
+                burst: 2.0,
+            },
+            gossip: Budget {
+                rate: 10.0,
+                burst: 10.0,
+            },
+        };
+        let mut limiter = RateLimiter::new(limits);
+        let peer = PeerIdHash::repeat_byte(1);
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.admit(peer, EthMessageId::GetBlockHeaders, now),
+            Admission::Accept
+        );
+        assert_eq!(
+            limiter.admit(peer, EthMessageId::GetBlockHeaders, now),
+            Admission::Accept
+        );
+        assert_eq!(
+            limiter.admit(peer, EthMessageId::GetBlockHeaders, now),
+            Admission::Drop
+        );
+        // Budgets are per message type and refill over time.
+        assert_eq!(
+            limiter.admit(peer, EthMessageId::GetBlockBodies, now),
+            Admission::Accept
+        );
+        assert_eq!(
+            limiter.admit(
+                peer,
+                EthMessageId::GetBlockHeaders,
+                now + Duration::from_secs(1)
+            ),
+            Admission::Accept
+        );
+        // Responses are not limited.
+        for _ in 0..10 {
+            assert_eq!(
+                limiter.admit(peer, EthMessageId::BlockHeaders, now),
+                Admission::Accept
+            );
+        }
+
+        let mut admissions = (0..200).map(|_| limiter.admit(peer, EthMessageId::GetReceipts, now));
+        assert!(admissions.any(|admission| admission == Admission::Disconnect));
+    }
+}
@@ -0,0 +1,402 @@
+use crate::{log_filter, Ban, CapabilityServerImpl, Opts, NR};
+use async_stream::stream;
+use devp2p::{BanTarget, NodeRecord, PeerId, Swarm};
+use futures::{stream::BoxStream, StreamExt};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::watch, time::sleep};
+use tracing::*;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// How often to check the config file for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Settings that can change without a restart.
+#[derive(Clone, Debug)]
+pub struct ReloadableSettings {
+    pub max_peers: usize,
+    pub bans: HashSet<BanTarget>,
+    pub static_peers: HashMap<SocketAddr, PeerId>,
+    pub trusted_peers: HashMap<PeerId, SocketAddr>,
+    pub log_filter: Option<String>,
+}
+
+impl From<&Opts> for ReloadableSettings {
+    fn from(opts: &Opts) -> Self {
+        Self {
+            max_peers: opts.max_peers,
+            bans: opts.bans.iter().map(|&Ban(target)| target).collect(),
+            static_peers: opts
+                .static_peers
+                .iter()
+                .map(|&NR(NodeRecord { addr, id })| (addr, id))
+                .collect(),
+            trusted_peers: opts
+                .trusted_peers
+                .iter()
+                .map(|&NR(NodeRecord { addr, id })| (id, addr))
+                .collect(),
+            log_filter: opts.log_filter.clone(),
+        }
+    }
+}
+
+/// What applying settings changes. `None` and empty fields are left as they are.
+#[derive(Debug, Default, PartialEq)]
+struct Changes {
+    log_filter: Option<Option<String>>,
+    max_peers: Option<usize>,
+    unbans: Vec<BanTarget>,
+    bans: Vec<BanTarget>,
+    static_peers: Option<HashMap<SocketAddr, PeerId>>,
+    trusted_peers: Option<HashMap<PeerId, SocketAddr>>,
+}
+
+impl ReloadableSettings {
+    /// Changes to make for these settings, given the ones loaded before. Everything is applied
+    /// on first load, afterwards only what differs from the previous load.
+    fn changes_since(&self, loaded: Option<&Self>) -> Changes {
+        fn changed<T: Clone + PartialEq>(value: &T, loaded: Option<&T>) -> Option<T> {
+            (loaded != Some(value)).then(|| value.clone())
+        }
+
+        let no_bans = HashSet::new();
+        let loaded_bans = loaded.map_or(&no_bans, |loaded| &loaded.bans);
+        Changes {
+            log_filter: changed(&self.log_filter, loaded.map(|loaded| &loaded.log_filter)),
+            max_peers: changed(&self.max_peers, loaded.map(|loaded| &loaded.max_peers)),
+            unbans: loaded_bans.difference(&self.bans).copied().collect(),
+            bans: self.bans.difference(loaded_bans).copied().collect(),
+            static_peers: changed(
+                &self.static_peers,
+                loaded.map(|loaded| &loaded.static_peers),
+            ),
+            trusted_peers: changed(
+                &self.trusted_peers,
+                loaded.map(|loaded| &loaded.trusted_peers),
+            ),
+        }
+    }
+}
+
+/// Settings that only change on restart, as `Debug` strings to tell changes by.
+pub struct RestartSettings(Vec<(&'static str, String)>);
+
+impl From<&Opts> for RestartSettings {
+    fn from(opts: &Opts) -> Self {
+        macro_rules! settings {
+            ($($field:ident),* $(,)?) => {
+                vec![$((stringify!($field), format!("{:?}", opts.$field))),*]
+            };
+        }
+
+        Self(settings!(
+            node_key,
+            node_key_file,
+            import_node_key,
+            export_node_key,
+            listen_port,
+            external_ip,
+            cidr,
+            sentry_addr,
+            tls_cert,
+            tls_key,
+            tls_client_ca,
+            auth_token,
+            dnsdisc_address,
+            discv4_port,
+            discv4_bootnodes,
+            discv4_cache,
+            discv4_concurrent_lookups,
+            discv5,
+            discv5_enr,
+            discv5_addr,
+            discv5_bootnodes,
+            static_peers_interval,
+            no_discovery,
+            peers_file,
+            message_replay_buffer,
+            min_ready_peers,
+            request_rate,
+            request_burst,
+            gossip_rate,
+            gossip_burst,
+            outbound_queue_capacity,
+            admin_addr,
+            admin_token,
+            metrics_addr,
+            tokio_console,
+        ))
+    }
+}
+
+impl RestartSettings {
+    /// Names of the settings that differ in `other`.
+    fn changed(&self, other: &Self) -> Vec<&'static str> {
+        self.0
+            .iter()
+            .zip(&other.0)
+            .filter(|((_, value), (_, other_value))| value != other_value)
+            .map(|(&(name, _), _)| name)
+            .collect()
+    }
+}
+
+/// Reloadable settings of a running sentry.
+pub struct LiveSettings {
+    swarm: Arc<Swarm<CapabilityServerImpl>>,
+    max_peers: Arc<AtomicUsize>,
+    static_peers: watch::Sender<HashMap<SocketAddr, PeerId>>,
+    log_filter: LogFilterHandle,
+    /// Settings as last loaded. Only the ones changed since get applied, so that reloads keep
+    /// runtime changes, like admin API bans and peer limit, to settings left alone.
+    loaded: Option<ReloadableSettings>,
+}
+
+impl LiveSettings {
+    /// `max_peers` must be the limit the swarm's node filter reads.
+    pub fn new(
+        swarm: Arc<Swarm<CapabilityServerImpl>>,
+        max_peers: Arc<AtomicUsize>,
+        static_peers: watch::Sender<HashMap<SocketAddr, PeerId>>,
+        log_filter: LogFilterHandle,
+    ) -> Self {
+        Self {
+            swarm,
+            max_peers,
+            static_peers,
+            log_filter,
+            loaded: None,
+        }
+    }
+
+    /// Settings not covered here only change on restart.
+    pub async fn apply(&mut self, settings: ReloadableSettings) -> anyhow::Result<()> {
+        let changes = settings.changes_since(self.loaded.as_ref());
+
+        if let Some(directives) = changes.log_filter {
+            self.log_filter.reload(log_filter(directives.as_deref())?)?;
+        }
+
+        if let Some(max_peers) = changes.max_peers {
+            let previous = self.max_peers.swap(max_peers, Ordering::Relaxed);
+            if previous != max_peers {
+                info!("Max peers changed from {} to {}", previous, max_peers);
+            }
+        }
+
+        if !changes.unbans.is_empty() || !changes.bans.is_empty() {
+            {
+                let mut node_filter = self.swarm.node_filter.lock();
+                for target in changes.unbans {
+                    info!("Unbanning {:?}", target);
+                    match target {
+                        BanTarget::Node(id) => node_filter.unban(id),
+                        BanTarget::Ip(ip) => node_filter.unban_ip(ip),
+                    }
+                }
+                for target in changes.bans {
+                    info!("Banning {:?} permanently", target);
+                    match target {
+                        BanTarget::Node(id) => node_filter.ban(id, None),
+                        BanTarget::Ip(ip) => node_filter.ban_ip(ip, None),
+                    }
+                }
+            }
+            self.swarm.disconnect_banned().await;
+        }
+
+        if let Some(static_peers) = changes.static_peers {
+            // Cannot fail as the static peers discovery holds a receiver.
+            let _ = self.static_peers.send(static_peers);
+        }
+        if let Some(trusted_peers) = changes.trusted_peers {
+            self.swarm.set_trusted_peers(trusted_peers);
+        }
+
+        self.loaded = Some(settings);
+
+        Ok(())
+    }
+}
+
+/// SIGHUPs, never any on platforms without them.
+fn hangups() -> anyhow::Result<BoxStream<'static, ()>> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        Ok(Box::pin(stream! {
+            while hangup.recv().await.is_some() {
+                yield ();
+            }
+        }))
+    }
+    #[cfg(not(unix))]
+    Ok(Box::pin(futures::stream::pending()))
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Re-read the settings on SIGHUP or when the config file changes, and apply the ones that can change live.
+/// Non-reloadable settings that differ from `running` only get a warning.
+pub async fn reload_on_change(
+    mut settings: LiveSettings,
+    running: RestartSettings,
+    config: PathBuf,
+) {
+    let mut hangups = hangups().unwrap_or_else(|e| {
+        warn!("Failed to listen for SIGHUP: {}", e);
+        Box::pin(futures::stream::pending())
+    });
+    let mut modified = modified_at(&config);
+    loop {
+        tokio::select! {
+            Some(()) = hangups.next() => {
+                info!("Received SIGHUP, reloading settings");
+            }
+            _ = sleep(CHECK_INTERVAL) => {
+                if modified_at(&config) == modified {
+                    continue;
+                }
+                info!("Config file {} changed, reloading settings", config.display());
+            }
+        }
+        modified = modified_at(&config);
+
+        match Opts::reload() {
+            Ok(opts) => {
+                let changed = running.changed(&RestartSettings::from(&opts));
+                if !changed.is_empty() {
+                    warn!(
+                        "Changed settings need a restart to take effect: {}",
+                        changed.join(", ")
+                    );
+                }
+                if let Err(e) = settings.apply((&opts).into()).await {
+                    warn!("Failed to apply reloaded settings: {:?}", e);
+                }
+            }
+            Err(e) => warn!(
+                "Failed to reload settings, keeping the current ones: {:?}",
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{IntoApp, Parser};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn opts(args: &[&str]) -> Opts {
+        Opts::try_parse_from(std::iter::once("sentry").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn restart_settings() {
+        let running = RestartSettings::from(&opts(&[]));
+        assert!(running
+            .changed(&RestartSettings::from(&opts(&["--max-peers", "10"])))
+            .is_empty());
+        assert_eq!(
+            running.changed(&RestartSettings::from(&opts(&[
+                "--listen-port",
+                "30304",
+                "--max-peers",
+                "10",
+                "--auth-token",
+                "secret",
+            ]))),
+            vec!["listen_port", "auth_token"]
+        );
+    }
+
+    #[test]
+    fn restart_settings_cover_all_settings() {
+        let reloadable = [
+            "config",
+            "max_peers",
+            "bans",
+            "static_peers",
+            "trusted_peers",
+            "log_filter",
+        ];
+        let restart_only = RestartSettings::from(&opts(&[]))
+            .0
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<HashSet<_>>();
+
+        for arg in Opts::into_app().get_arguments() {
+            let name = arg.get_name();
+            if arg.get_long().is_none() || name == "help" || name == "version" {
+                continue;
+            }
+            assert!(
+                reloadable.contains(&name) != restart_only.contains(name),
+                "`{}` must be either reloadable or listed in RestartSettings",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn changes() {
+        let node = BanTarget::Node(PeerId::repeat_byte(1));
+        let ip = BanTarget::Ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let loaded = ReloadableSettings {
+            max_peers: 50,
+            bans: HashSet::from([node, ip]),
+            static_peers: Default::default(),
+            trusted_peers: Default::default(),
+            log_filter: None,
+        };
+
+        // Everything applies on first load.
+        let first = loaded.changes_since(None);
+        assert_eq!(first.max_peers, Some(50));
+        assert_eq!(first.bans.len(), 2);
+        assert_eq!(first.log_filter, Some(None));
+
+        // Nothing changed, so runtime changes to the same settings are kept.
+        assert_eq!(
+            loaded.clone().changes_since(Some(&loaded)),
+            Changes::default()
+        );
+
+        // Only the removed ban is lifted and only the added one is applied.
+        let other = BanTarget::Node(PeerId::repeat_byte(2));
+        let settings = ReloadableSettings {
+            max_peers: 100,
+            bans: HashSet::from([node, other]),
+            ..loaded.clone()
+        };
+        assert_eq!(
+            settings.changes_since(Some(&loaded)),
+            Changes {
+                max_peers: Some(100),
+                unbans: vec![ip],
+                bans: vec![other],
+                ..Default::default()
+            }
+        );
+    }
+}
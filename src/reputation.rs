@@ -0,0 +1,148 @@
+use devp2p::{DisconnectReason, PeerId};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Peers at or below this score get disconnected.
+pub const DISCONNECT_THRESHOLD: i64 = -100;
+/// Peers at or below this score get disconnected and banned for `BAN_DURATION`.
+pub const BAN_THRESHOLD: i64 = -200;
+pub const BAN_DURATION: Duration = Duration::from_secs(30 * 60);
+
+/// Time it takes for a score to get halfway back to neutral.
+const DECAY_HALF_LIFE: Duration = Duration::from_secs(10 * 60);
+/// Scores closer to neutral than this are forgotten when pruning.
+const NEGLIGIBLE_SCORE: f64 = 1.0;
+const MAX_TRACKED_PEERS: usize = 16384;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReputationChange {
+    /// Core asked to kick the peer.
+    Kick,
+    /// Malformed or unexpected message.
+    ProtocolBreach,
+    /// Peer is on another chain or otherwise of no use to us.
+    UselessPeer,
+    /// Peer did not respond in time.
+    Timeout,
+}
+
+impl ReputationChange {
+    /// Reputation change for disconnecting the peer with the given reason, if it's peer's fault.
+    pub fn for_disconnect(reason: DisconnectReason) -> Option<Self> {
+        match reason {
+            DisconnectReason::ProtocolBreach => Some(Self::ProtocolBreach),
+            DisconnectReason::UselessPeer => Some(Self::UselessPeer),
+            DisconnectReason::PingTimeout => Some(Self::Timeout),
+            _ => None,
+        }
+    }
+
+    const fn value(self) -> i64 {
+        match self {
+            Self::Kick => -100,
+            Self::ProtocolBreach => -100,
+            Self::UselessPeer => -50,
+            Self::Timeout => -25,
+        }
+    }
+}
+
+/// What should happen to the peer after its reputation changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Keep,
+    Disconnect,
+    Ban,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Score {
+    value: f64,
+    updated_at: Instant,
+}
+
+impl Score {
+    fn decayed(&self, now: Instant) -> f64 {
+        let half_lives = now.saturating_duration_since(self.updated_at).as_secs_f64()
+            / DECAY_HALF_LIFE.as_secs_f64();
+        self.value * 0.5_f64.powf(half_lives)
+    }
+}
+
+/// Reputation scores of nodes, kept across reconnects and decaying towards neutral over time.
+#[derive(Debug, Default)]
+pub struct Reputation {
+    scores: HashMap<PeerId, Score>,
+}
+
+impl Reputation {
+    pub fn score(&self, id: PeerId) -> i64 {
+        self.scores
+            .get(&id)
+            .map_or(0, |score| score.decayed(Instant::now()) as i64)
+    }
+
+    pub fn report(&mut self, id: PeerId, change: ReputationChange) -> Verdict {
+        let now = Instant::now();
+        let score = self.scores.entry(id).or_insert(Score {
+            value: 0.0,
+            updated_at: now,
+        });
+        score.value = score.decayed(now) + change.value() as f64;
+        score.updated_at = now;
+        let value = score.value as i64;
+
+        if self.scores.len() > MAX_TRACKED_PEERS {
+            self.prune(now);
+        }
+
+        if value <= BAN_THRESHOLD {
+            Verdict::Ban
+        } else if value <= DISCONNECT_THRESHOLD {
+            Verdict::Disconnect
+        } else {
+            Verdict::Keep
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        self.scores
+            .retain(|_, score| score.decayed(now).abs() >= NEGLIGIBLE_SCORE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds() {
+        let mut reputation = Reputation::default();
+        let id = PeerId::repeat_byte(1);
+
+        assert_eq!(
+            reputation.report(id, ReputationChange::Timeout),
+            Verdict::Keep
+        );
+        assert_eq!(
+            reputation.report(id, ReputationChange::ProtocolBreach),
+            Verdict::Disconnect
+        );
+        assert_eq!(reputation.report(id, ReputationChange::Kick), Verdict::Ban);
+        assert_eq!(reputation.score(PeerId::repeat_byte(2)), 0);
+    }
+
+    #[test]
+    fn decay() {
+        let now = Instant::now();
+        let score = Score {
+            value: -200.0,
+            updated_at: now,
+        };
+
+        assert_eq!(score.decayed(now + DECAY_HALF_LIFE) as i64, -100);
+        assert_eq!(score.decayed(now + DECAY_HALF_LIFE * 2) as i64, -50);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::eth::EthMessageId;
+use devp2p::PeerIdHash;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Requests not answered within this time count as timed out.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Clone, Copy, Debug)]
+struct PendingRequest {
+    response_id: EthMessageId,
+    /// ID the core sent the request with.
+    core_request_id: u64,
+    sent_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct PeerRequests {
+    pending: HashMap<u64, PendingRequest>,
+    /// Highest request ID sent to the peer, to tell late responses from unsolicited ones.
+    last_request_id: u64,
+    average_latency: Option<Duration>,
+    timed_out: u64,
+}
+
+/// How a response relates to the requests sent to the peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseMatch {
+    /// Answers a pending request sent this long ago by the core with this request ID.
+    Answered {
+        latency: Duration,
+        core_request_id: u64,
+    },
+    /// Answers a request that already timed out or never made it to the peer.
+    Late,
+    /// No request with this ID and message type was sent to the peer.
+    Unsolicited,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestStats {
+    pub pending: usize,
+    pub timed_out: u64,
+    /// Moving average of response latency.
+    pub average_latency: Option<Duration>,
+}
+
+/// Outstanding eth/66 requests sent to peers.
+///
+/// Requests go out under request IDs assigned here rather than the ones cores chose, so that
+/// requests of different cores never collide.
+#[derive(Debug, Default)]
+pub struct RequestTracker {
+    peers: HashMap<PeerIdHash, PeerRequests>,
+    last_request_id: u64,
+}
+
+impl RequestTracker {
+    /// Returns the request ID to send the request under, if it expects a response.
+    pub fn on_request(
+        &mut self,
+        peer: PeerIdHash,
+        id: EthMessageId,
+        core_request_id: u64,
+    ) -> Option<u64> {
+        let response_id = id.response_id()?;
+        self.last_request_id += 1;
+        let request_id = self.last_request_id;
+
+        let requests = self.peers.entry(peer).or_default();
+        requests.last_request_id = request_id;
+        requests.pending.insert(
+            request_id,
+            PendingRequest {
+                response_id,
+                core_request_id,
+                sent_at: Instant::now(),
+            },
+        );
+
+        Some(request_id)
+    }
+
+    /// Forget a request that never made it to the peer.
+    pub fn cancel(&mut self, peer: PeerIdHash, request_id: u64) {
+        if let Some(requests) = self.peers.get_mut(&peer) {
+            requests.pending.remove(&request_id);
+        }
+    }
+
+    pub fn on_response(
+        &mut self,
+        peer: PeerIdHash,
+        id: EthMessageId,
+        request_id: u64,
+    ) -> ResponseMatch {
+        let requests = if let Some(requests) = self.peers.get_mut(&peer) {
+            requests
+        } else {
+            return ResponseMatch::Unsolicited;
+        };
+
+        match requests.pending.get(&request_id) {
+            Some(request) if request.response_id == id => {
+                let request = requests.pending.remove(&request_id).unwrap();
+                let latency = request.sent_at.elapsed();
+                // Same smoothing factor as TCP uses for its round-trip time estimate.
+                requests.average_latency = Some(
+                    requests
+                        .average_latency
+                        .map_or(latency, |average| (average * 7 + latency) / 8),
+                );
+
+                ResponseMatch::Answered {
+                    latency,
+                    core_request_id: request.core_request_id,
+                }
+            }
+            None if request_id <= requests.last_request_id => ResponseMatch::Late,
+            _ => ResponseMatch::Unsolicited,
+        }
+    }
+
+    /// Forgets requests older than `REQUEST_TIMEOUT` and returns them.
+    pub fn expire(&mut self, now: Instant) -> Vec<(PeerIdHash, EthMessageId, u64)> {
+        let mut expired = Vec::new();
+        for (&peer, requests) in &mut self.peers {
+            let timed_out = &mut requests.timed_out;
+            requests.pending.retain(|&request_id, request| {
+                if now.saturating_duration_since(request.sent_at) < REQUEST_TIMEOUT {
+                    return true;
+                }
+
+                *timed_out += 1;
+                expired.push((peer, request.response_id, request_id));
+                false
+            });
+        }
+
+        expired
+    }
+
+    pub fn stats(&self, peer: PeerIdHash) -> RequestStats {
+        self.peers
+            .get(&peer)
+            .map(|requests| RequestStats {
+                pending: requests.pending.len(),
+                timed_out: requests.timed_out,
+                average_latency: requests.average_latency,
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn remove_peer(&mut self, peer: PeerIdHash) {
+        self.peers.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_responses() {
+        let mut tracker = RequestTracker::default();
+        let peer = PeerIdHash::repeat_byte(1);
+
+        // Two cores that happen to pick the same request ID.
+        let headers = tracker
+            .on_request(peer, EthMessageId::GetBlockHeaders, 1)
+            .unwrap();
+        let bodies = tracker
+            .on_request(peer, EthMessageId::GetBlockBodies, 1)
+            .unwrap();
+        assert_ne!(headers, bodies);
+        assert_eq!(
+            tracker.on_request(peer, EthMessageId::Transactions, 1),
+            None
+        );
+
+        assert_eq!(
+            tracker.on_response(peer, EthMessageId::BlockBodies, headers),
+            ResponseMatch::Unsolicited
+        );
+        assert!(matches!(
+            tracker.on_response(peer, EthMessageId::BlockHeaders, headers),
+            ResponseMatch::Answered {
+                core_request_id: 1,
+                ..
+            }
+        ));
+        assert_eq!(
+            tracker.on_response(peer, EthMessageId::BlockHeaders, headers),
+            ResponseMatch::Late
+        );
+        assert_eq!(
+            tracker.on_response(peer, EthMessageId::BlockBodies, bodies + 1),
+            ResponseMatch::Unsolicited
+        );
+        assert_eq!(
+            tracker.on_response(
+                PeerIdHash::repeat_byte(2),
+                EthMessageId::BlockBodies,
+                bodies
+            ),
+            ResponseMatch::Unsolicited
+        );
+        assert_eq!(tracker.stats(peer).pending, 1);
+    }
+
+    #[test]
+    fn expires_requests() {
+        let mut tracker = RequestTracker::default();
+        let peer = PeerIdHash::repeat_byte(1);
+        let request_id = tracker
+            .on_request(peer, EthMessageId::GetReceipts, 7)
+            .unwrap();
+
+        let now = Instant::now();
+        assert!(tracker.expire(now).is_empty());
+        assert_eq!(
+            tracker.expire(now + REQUEST_TIMEOUT),
+            vec![(peer, EthMessageId::Receipts, request_id)]
+        );
+        assert!(tracker.expire(now + REQUEST_TIMEOUT).is_empty());
+        assert_eq!(tracker.stats(peer).timed_out, 1);
+        assert_eq!(tracker.stats(peer).pending, 0);
+
+        // However late, a response to a request we sent is not unsolicited.
+        assert_eq!(
+            tracker.on_response(peer, EthMessageId::Receipts, request_id),
+            ResponseMatch::Late
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::{bail, Context};
+use std::{path::Path, sync::Arc};
+use tonic::{
+    service::Interceptor,
+    transport::{Certificate, Identity, ServerTlsConfig},
+    Request, Status,
+};
+
+/// Rejects gRPC requests without `authorization: Bearer <token>` metadata. Lets everything through if no token is set.
+#[derive(Clone, Debug, Default)]
+pub struct TokenAuth {
+    token: Option<Arc<str>>,
+}
+
+impl TokenAuth {
+    pub fn new(token: Option<String>) -> Self {
+        Self {
+            token: token.map(Into::into),
+        }
+    }
+}
+
+/// Compares in time independent of where the inputs differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+impl Interceptor for TokenAuth {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let token = if let Some(token) = &self.token {
+            token
+        } else {
+            return Ok(request);
+        };
+
+        let provided = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("no bearer token"))?;
+
+        if !constant_time_eq(provided.as_bytes(), token.as_bytes()) {
+            return Err(Status::unauthenticated("invalid bearer token"));
+        }
+
+        Ok(request)
+    }
+}
+
+async fn read(path: &Path) -> anyhow::Result<Vec<u8>> {
+    tokio::fs::read(path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))
+}
+
+/// TLS config of the gRPC server, if enabled.
+pub async fn tls_config(
+    cert: Option<&Path>,
+    key: Option<&Path>,
+    client_ca: Option<&Path>,
+) -> anyhow::Result<Option<ServerTlsConfig>> {
+    let (cert, key) = match (cert, key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => {
+            if client_ca.is_some() {
+                bail!("client certificate verification requires --tls-cert and --tls-key");
+            }
+            return Ok(None);
+        }
+        _ => bail!("--tls-cert and --tls-key must be specified together"),
+    };
+
+    let mut config =
+        ServerTlsConfig::new().identity(Identity::from_pem(read(cert).await?, read(key).await?));
+    if let Some(client_ca) = client_ca {
+        config = config.client_ca_root(Certificate::from_pem(read(client_ca).await?));
+    }
+
+    Ok(Some(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_auth() {
+        let request = |token: Option<&str>| {
+            let mut request = Request::new(());
+            if let Some(token) = token {
+                request
+                    .metadata_mut()
+                    .insert("authorization", token.parse().unwrap());
+            }
+            request
+        };
+
+        let mut auth = TokenAuth::new(Some("secret".into()));
+        assert!(auth.call(request(Some("Bearer secret"))).is_ok());
+        assert!(auth.call(request(Some("Bearer secre"))).is_err());
+        assert!(auth.call(request(Some("secret"))).is_err());
+        assert!(auth.call(request(None)).is_err());
+
+        assert!(TokenAuth::default().call(request(None)).is_ok());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::{
+    proto::admin::{
+        admin_server::Admin, ban_target::Target, AddPeerReply, AddPeerRequest, Ban, BanReply,
+        BanRequest, BanTarget as ProtoBanTarget, ListBansReply, ListBansRequest, RemovePeerReply,
+        RemovePeerRequest, SetMaxPeersReply, SetMaxPeersRequest, UnbanReply, UnbanRequest,
+    },
+    CapabilityServerImpl, Delivery,
+};
+use async_trait::async_trait;
+use devp2p::{
+    node_record_from_enr, BanTarget, DisconnectReason, NodeRecord, OutboundEvent, PeerId,
+    PeerIdHash, Swarm,
+};
+use futures::future::join_all;
+use num_traits::FromPrimitive;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tonic::Response;
+use tracing::*;
+
+fn node_record(node: &str) -> Result<NodeRecord, tonic::Status> {
+    if node.starts_with("enode://") {
+        node.parse()
+            .map_err(|e| tonic::Status::invalid_argument(format!("invalid enode: {}", e)))
+    } else if node.starts_with("enr:") {
+        let enr = node
+            .parse::<discv5::Enr>()
+            .map_err(|e| tonic::Status::invalid_argument(format!("invalid ENR: {}", e)))?;
+        node_record_from_enr(&enr).ok_or_else(|| {
+            tonic::Status::invalid_argument("ENR has no TCP endpoint or secp256k1 key")
+        })
+    } else {
+        Err(tonic::Status::invalid_argument(
+            "expected an enode:// URL or an ENR",
+        ))
+    }
+}
+
+fn node_id(id: &[u8]) -> Result<PeerId, tonic::Status> {
+    if id.len() != PeerId::len_bytes() {
+        return Err(tonic::Status::invalid_argument("node ID must be 64 bytes"));
+    }
+
+    Ok(PeerId::from_slice(id))
+}
+
+fn ban_target(target: Option<ProtoBanTarget>) -> Result<BanTarget, tonic::Status> {
+    match target.and_then(|target| target.target) {
+        Some(Target::NodeId(id)) => Ok(BanTarget::Node(node_id(&id)?)),
+        Some(Target::Ip(ip)) => Ok(BanTarget::Ip(ip.parse().map_err(|_| {
+            tonic::Status::invalid_argument(format!("invalid IP address: {}", ip))
+        })?)),
+        None => Err(tonic::Status::invalid_argument("no ban target")),
+    }
+}
+
+impl From<BanTarget> for ProtoBanTarget {
+    fn from(target: BanTarget) -> Self {
+        Self {
+            target: Some(match target {
+                BanTarget::Node(id) => Target::NodeId(id.as_bytes().to_vec()),
+                BanTarget::Ip(ip) => Target::Ip(ip.to_string()),
+            }),
+        }
+    }
+}
+
+/// Operator API to manage peers of a running sentry.
+pub struct AdminService {
+    swarm: Arc<Swarm<CapabilityServerImpl>>,
+    max_peers: Arc<AtomicUsize>,
+}
+
+impl AdminService {
+    /// `max_peers` must be the limit the swarm's node filter reads.
+    pub fn new(swarm: Arc<Swarm<CapabilityServerImpl>>, max_peers: Arc<AtomicUsize>) -> Self {
+        Self { swarm, max_peers }
+    }
+
+    /// Disconnect the peers, returning the ones the disconnect was queued for.
+    async fn disconnect(
+        &self,
+        peers: Vec<PeerIdHash>,
+        reason: DisconnectReason,
+    ) -> Vec<PeerIdHash> {
+        join_all(peers.into_iter().map(|peer| async move {
+            let delivery = self
+                .swarm
+                .deliver(peer, OutboundEvent::Disconnect { reason })
+                .await;
+            (peer, delivery)
+        }))
+        .await
+        .into_iter()
+        .filter(|(_, delivery)| *delivery == Delivery::Accepted)
+        .map(|(peer, _)| peer)
+        .collect()
+    }
+}
+
+#[async_trait]
+impl Admin for AdminService {
+    async fn add_peer(
+        &self,
+        request: tonic::Request<AddPeerRequest>,
+    ) -> Result<Response<AddPeerReply>, tonic::Status> {
+        let record = node_record(&request.into_inner().node)?;
+
+        info!("Adding peer {} at {}", record.id, record.addr);
+        let added = self
+            .swarm
+            .add_peer(record)
+            .await
+            .map_err(|e| tonic::Status::unavailable(format!("failed to connect: {}", e)))?;
+
+        Ok(Response::new(AddPeerReply { added }))
+    }
+
+    async fn remove_peer(
+        &self,
+        request: tonic::Request<RemovePeerRequest>,
+    ) -> Result<Response<RemovePeerReply>, tonic::Status> {
+        let RemovePeerRequest {
+            node_id: id,
+            reason,
+        } = request.into_inner();
+        let id = node_id(&id)?;
+        let reason = DisconnectReason::from_u32(reason)
+            .ok_or_else(|| tonic::Status::invalid_argument("unknown disconnect reason"))?;
+
+        info!("Removing peer {} ({:?})", id, reason);
+        self.swarm.known_peers.lock().forget(id);
+        let peer = self.swarm.get_hash(id);
+        let connected = if self.swarm.all_peers().contains(&peer) {
+            !self.disconnect(vec![peer], reason).await.is_empty()
+        } else {
+            false
+        };
+
+        Ok(Response::new(RemovePeerReply { connected }))
+    }
+
+    async fn ban(
+        &self,
+        request: tonic::Request<BanRequest>,
+    ) -> Result<Response<BanReply>, tonic::Status> {
+        let BanRequest {
+            target,
+            duration_secs,
+        } = request.into_inner();
+        let target = ban_target(target)?;
+        let until = if duration_secs == 0 {
+            None
+        } else {
+            // Durations too long to represent are as good as permanent.
+            Instant::now().checked_add(Duration::from_secs(duration_secs))
+        };
+
+        match until {
+            Some(_) => info!("Banning {:?} for {}s", target, duration_secs),
+            None => info!("Banning {:?} permanently", target),
+        }
+        {
+            let mut node_filter = self.swarm.node_filter.lock();
+            match target {
+                BanTarget::Node(id) => node_filter.ban(id, until),
+                BanTarget::Ip(ip) => node_filter.ban_ip(ip, until),
+            }
+        }
+
+        let banned = self
+            .swarm
+            .all_peer_info()
+            .into_iter()
+            .filter(|(_, info)| match target {
+                BanTarget::Node(id) => info.id == id,
+                BanTarget::Ip(ip) => info
+                    .connection
+                    .remote_addr
+                    .map_or(false, |addr| addr.ip().to_canonical() == ip.to_canonical()),
+            })
+            .collect::<Vec<_>>();
+        {
+            let mut known_peers = self.swarm.known_peers.lock();
+            for (_, info) in &banned {
+                known_peers.forget(info.id);
+            }
+        }
+        let disconnected = self
+            .disconnect(
+                banned.into_iter().map(|(peer, _)| peer).collect(),
+                DisconnectReason::DisconnectRequested,
+            )
+            .await;
+
+        Ok(Response::new(BanReply {
+            disconnected: disconnected
+                .into_iter()
+                .map(|peer| peer.as_bytes().to_vec())
+                .collect(),
+        }))
+    }
+
+    async fn unban(
+        &self,
+        request: tonic::Request<UnbanRequest>,
+    ) -> Result<Response<UnbanReply>, tonic::Status> {
+        let target = ban_target(request.into_inner().target)?;
+
+        info!("Unbanning {:?}", target);
+        let mut node_filter = self.swarm.node_filter.lock();
+        match target {
+            BanTarget::Node(id) => node_filter.unban(id),
+            BanTarget::Ip(ip) => node_filter.unban_ip(ip),
+        }
+
+        Ok(Response::new(UnbanReply {}))
+    }
+
+    async fn list_bans(
+        &self,
+        _: tonic::Request<ListBansRequest>,
+    ) -> Result<Response<ListBansReply>, tonic::Status> {
+        let now = Instant::now();
+        let bans = self
+            .swarm
+            .node_filter
+            .lock()
+            .bans()
+            .into_iter()
+            .map(|(target, until)| Ban {
+                target: Some(target.into()),
+                remaining_secs: until.map_or(0, |until| {
+                    // Round up so that a ban in effect is never reported as permanent.
+                    let remaining = until.saturating_duration_since(now);
+                    remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0)
+                }),
+            })
+            .collect();
+
+        Ok(Response::new(ListBansReply { bans }))
+    }
+
+    async fn set_max_peers(
+        &self,
+        request: tonic::Request<SetMaxPeersRequest>,
+    ) -> Result<Response<SetMaxPeersReply>, tonic::Status> {
+        let max_peers = request.into_inner().max_peers as usize;
+
+        let previous = self.max_peers.swap(max_peers, Ordering::Relaxed);
+        info!("Max peers changed from {} to {}", previous, max_peers);
+
+        Ok(Response::new(SetMaxPeersReply {
+            previous: previous as u64,
+        }))
+    }
+}
@@ -1,3 +1,5 @@
+mod admin;
 mod sentry;
+mod sentry_ext;
 
-pub use self::sentry::*;
+pub use self::{admin::*, sentry::*, sentry_ext::*};
@@ -1,18 +1,34 @@
-use crate::{eth::*, CapabilityServerImpl};
+use crate::{
+    eth::*,
+    grpc::OutboundMessageId,
+    reputation::{ReputationChange, Verdict},
+    shutdown::until_shutdown,
+    CapabilityServerImpl, Delivery,
+};
 use async_trait::async_trait;
+use bytes::Bytes;
 use devp2p::*;
+use educe::Educe;
 use ethereum_interfaces::{
     sentry::{
-        sentry_server::*, HandShakeReply, InboundMessage, MessageId as ProtoMessageId,
-        OutboundMessageData, PeerMinBlockRequest, PeersReply, PeersRequest, SentPeers,
-        SetStatusReply,
+        sentry_server::*, HandShakeReply, InboundMessage, OutboundMessageData, PeerMinBlockRequest,
+        PeersReply, PeersRequest, PenalizePeerRequest, PenaltyKind, SentPeers, SetStatusReply,
     },
-    types::NodeInfoReply,
+    types::{NodeInfoPorts, NodeInfoReply},
 };
+use ethereum_types::H256;
 use futures::{Stream, TryStreamExt};
 use num_traits::ToPrimitive;
-use secp256k1::rand::seq::IteratorRandom;
-use std::{collections::HashSet, convert::TryFrom, pin::Pin, sync::Arc};
+use rlp::{DecoderError, Rlp, RlpStream};
+use secp256k1::{rand::seq::IteratorRandom, PublicKey, SecretKey, SECP256K1};
+use serde_json::json;
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Once},
+};
 use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 use tonic::Response;
 use tracing::*;
@@ -23,17 +39,366 @@ pub type InboundMessageStream =
 pub type PeersReplyStream =
     Pin<Box<dyn Stream<Item = anyhow::Result<PeersReply, tonic::Status>> + Send + Sync>>;
 
+/// Identity and addresses of the local node, reported by `Sentry::node_info`.
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct NodeInfo {
+    #[educe(Debug(ignore))]
+    pub secret_key: SecretKey,
+    pub client_version: String,
+    pub listen_addr: SocketAddr,
+    pub discovery_port: Option<u16>,
+    pub discv5_enr: Option<discv5::Enr>,
+    /// IP set by `--external-ip`.
+    pub external_ip: Option<IpAddr>,
+    /// Learns the IP peers see us at, absent an external IP.
+    #[educe(Debug(ignore))]
+    pub discv4_node: Option<Arc<discv4::Node>>,
+}
+
+impl NodeInfo {
+    fn peer_id(&self) -> PeerId {
+        peer_id_from_pub_key(&PublicKey::from_secret_key(SECP256K1, &self.secret_key))
+    }
+
+    /// Address other nodes should use to reach us: the configured external IP, a specific listen
+    /// address, or the IP discovery learned, in that order. Loopback only as a last resort.
+    fn advertised_ip(&self) -> IpAddr {
+        if let Some(ip) = self.external_ip {
+            return ip;
+        }
+
+        let ip = self.listen_addr.ip();
+        if !ip.is_unspecified() {
+            return ip;
+        }
+
+        if let Some(ip) = self
+            .discv4_node
+            .as_ref()
+            .and_then(|node| node.external_ip())
+        {
+            return ip;
+        }
+
+        // Cores poll node info, warning on every call would flood the log.
+        static LOOPBACK_WARNING: Once = Once::new();
+        LOOPBACK_WARNING.call_once(|| {
+            warn!("External IP unknown, advertising loopback; set --external-ip to fix")
+        });
+        Ipv4Addr::LOCALHOST.into()
+    }
+
+    fn enode(&self, ip: IpAddr) -> String {
+        let port = self.listen_addr.port();
+        let mut enode = format!(
+            "enode://{}@{}",
+            hex::encode(self.peer_id().as_bytes()),
+            SocketAddr::new(ip, port)
+        );
+        if let Some(discovery_port) = self.discovery_port {
+            if discovery_port != port {
+                enode.push_str(&format!("?discport={}", discovery_port));
+            }
+        }
+        enode
+    }
+
+    fn enr(&self, ip: IpAddr) -> anyhow::Result<String> {
+        if let Some(enr) = &self.discv5_enr {
+            return Ok(enr.to_base64());
+        }
+
+        let mut builder = enr::EnrBuilder::new("v4");
+        builder.ip(ip).tcp(self.listen_addr.port());
+        if let Some(discovery_port) = self.discovery_port {
+            builder.udp(discovery_port);
+        }
+
+        Ok(builder
+            .build(&self.secret_key)
+            .map_err(|e| anyhow::anyhow!("failed to build ENR: {:?}", e))?
+            .to_base64())
+    }
+}
+
+/// Transactions carried by an outbound message, for filtering out the ones a peer already knows.
+enum TransactionGossip {
+    /// Raw transactions of a `Transactions` message.
+    Transactions(Vec<(H256, Bytes)>),
+    /// `NewPooledTransactionHashes`, typed if the core provided types and sizes.
+    Announcement(PooledTransactionAnnouncement),
+}
+
+impl TransactionGossip {
+    fn new(id: EthMessageId, data: &[u8]) -> anyhow::Result<Option<Self>> {
+        Ok(match id {
+            EthMessageId::Transactions => {
+                let rlp = Rlp::new(data);
+                if !rlp.is_list() {
+                    return Err(DecoderError::RlpExpectedToBeList.into());
+                }
+
+                Some(Self::Transactions(
+                    rlp.iter()
+                        .map(|tx| Ok((transaction_hash(&tx)?, Bytes::copy_from_slice(tx.as_raw()))))
+                        .collect::<Result<_, DecoderError>>()?,
+                ))
+            }
+            EthMessageId::NewPooledTransactionHashes => Some(Self::Announcement(
+                PooledTransactionAnnouncement::decode(data)?,
+            )),
+            _ => None,
+        })
+    }
+
+    fn hashes(&self) -> Vec<H256> {
+        match self {
+            Self::Transactions(transactions) => {
+                transactions.iter().map(|(hash, _)| *hash).collect()
+            }
+            Self::Announcement(announcement) => announcement.hashes().to_vec(),
+        }
+    }
+
+    /// Payload with only the transactions at `positions`.
+    fn select(&self, positions: &[usize], version: EthProtocolVersion) -> Option<Bytes> {
+        Some(match self {
+            Self::Transactions(transactions) => {
+                let mut s = RlpStream::new_list(positions.len());
+                for &i in positions {
+                    s.append_raw(&transactions[i].1, 1);
+                }
+                s.out().freeze()
+            }
+            Self::Announcement(PooledTransactionAnnouncement::Typed(announcement))
+                if version >= EthProtocolVersion::Eth68 =>
+            {
+                rlp::encode(&NewPooledTransactionHashes68 {
+                    types: positions.iter().map(|&i| announcement.types[i]).collect(),
+                    sizes: positions.iter().map(|&i| announcement.sizes[i]).collect(),
+                    hashes: positions.iter().map(|&i| announcement.hashes[i]).collect(),
+                })
+                .freeze()
+            }
+            Self::Announcement(_) if version >= EthProtocolVersion::Eth68 => return None,
+            Self::Announcement(announcement) => rlp::encode_list::<H256, _>(
+                &positions
+                    .iter()
+                    .map(|&i| announcement.hashes()[i])
+                    .collect::<Vec<_>>(),
+            )
+            .freeze(),
+        })
+    }
+}
+
+/// Outbound payload encoded for the eth versions that need different layouts.
+struct OutboundPayloads {
+    /// Payload for peers before eth/68.
+    legacy: Option<Bytes>,
+    /// Payload for eth/68 peers.
+    eth68: Option<Bytes>,
+    transactions: Option<TransactionGossip>,
+    transaction_hashes: Vec<H256>,
+}
+
+impl OutboundPayloads {
+    fn new(id: EthMessageId, data: Bytes) -> anyhow::Result<Self> {
+        let transactions = TransactionGossip::new(id, &data)?;
+        let (legacy, eth68) = match &transactions {
+            // Older peers only get the hashes.
+            Some(TransactionGossip::Announcement(PooledTransactionAnnouncement::Typed(
+                announcement,
+            ))) => (
+                Some(rlp::encode_list::<H256, _>(&announcement.hashes).freeze()),
+                Some(data),
+            ),
+            // Types and sizes cannot be recovered from the hashes alone.
+            Some(TransactionGossip::Announcement(PooledTransactionAnnouncement::Hashes(_))) => {
+                (Some(data), None)
+            }
+            _ => (Some(data.clone()), Some(data)),
+        };
+
+        Ok(Self {
+            legacy,
+            eth68,
+            transaction_hashes: transactions
+                .as_ref()
+                .map_or_else(Vec::new, TransactionGossip::hashes),
+            transactions,
+        })
+    }
+
+    fn for_version(&self, version: EthProtocolVersion) -> Option<Bytes> {
+        if version >= EthProtocolVersion::Eth68 {
+            self.eth68.clone()
+        } else {
+            self.legacy.clone()
+        }
+    }
+
+    /// Payload for the peer along with the transactions it carries. Transactions the peer already
+    /// knows are left out, `None` if nothing is left to send.
+    fn for_peer(
+        &self,
+        capability_server: &CapabilityServerImpl,
+        peer: PeerIdHash,
+        version: EthProtocolVersion,
+    ) -> Option<(Bytes, Vec<H256>)> {
+        let transactions = if let Some(transactions) = &self.transactions {
+            transactions
+        } else {
+            return Some((self.for_version(version)?, Vec::new()));
+        };
+
+        let hashes = &self.transaction_hashes;
+        let unknown = capability_server.unknown_transactions(peer, hashes);
+        if unknown.is_empty() {
+            return None;
+        }
+        if unknown.len() == hashes.len() {
+            return Some((self.for_version(version)?, hashes.clone()));
+        }
+
+        let data = transactions.select(&unknown, version)?;
+        Some((data, unknown.into_iter().map(|i| hashes[i]).collect()))
+    }
+}
+
 pub struct SentryService {
     capability_server: Arc<CapabilityServerImpl>,
+    node_info: NodeInfo,
 }
 
 impl SentryService {
-    pub fn new(capability_server: Arc<CapabilityServerImpl>) -> Self {
-        Self { capability_server }
+    pub fn new(capability_server: Arc<CapabilityServerImpl>, node_info: NodeInfo) -> Self {
+        Self {
+            capability_server,
+            node_info,
+        }
     }
 }
 
+/// Outcome of sending a message to a set of peers.
+#[derive(Debug, Default)]
+pub struct SendReport {
+    /// Peers whose outbound queue accepted the message.
+    pub sent: Vec<PeerIdHash>,
+    /// Peers whose queue stayed full for `DELIVERY_TIMEOUT`.
+    pub timed_out: Vec<PeerIdHash>,
+    /// Peers that disconnected or were never connected.
+    pub gone: Vec<PeerIdHash>,
+    /// Peers that know everything in the message already, or whose eth version cannot carry it.
+    pub skipped: Vec<PeerIdHash>,
+}
+
+/// Sends the message with sentry API ID `id` to the peers, reporting what became of it for each.
+pub async fn send_and_report(
+    cap: &Arc<CapabilityServerImpl>,
+    id: i32,
+    data: Bytes,
+    peers: impl IntoIterator<Item = PeerIdHash>,
+) -> anyhow::Result<SendReport> {
+    let outbound_id = OutboundMessageId::from_proto(id)?;
+    let payloads = OutboundPayloads::new(outbound_id.id, data)?;
+
+    let mut report = SendReport::default();
+    let tasks = peers
+        .into_iter()
+        .filter_map(|peer| {
+            let payload = match cap.peer_eth_version(peer) {
+                Some(version) if !outbound_id.is_supported_by(version) => {
+                    trace!(
+                        "Peer {} on eth/{} does not support {:?}, skipping",
+                        peer,
+                        version as usize,
+                        outbound_id
+                    );
+                    None
+                }
+                Some(version)
+                    if version >= EthProtocolVersion::Eth68 && payloads.eth68.is_none() =>
+                {
+                    trace!(
+                        "Peer {} on eth/68 needs transaction types and sizes, skipping",
+                        peer
+                    );
+                    cap.delivery_stats().on_skipped();
+                    None
+                }
+                Some(version) => {
+                    let payload = payloads.for_peer(cap, peer, version);
+                    if payload.is_none() {
+                        trace!(
+                            "Message {:?} has nothing new for peer {}, skipping",
+                            outbound_id,
+                            peer
+                        );
+                    }
+                    payload
+                }
+                // Peer is gone, delivery will account for it.
+                None => payloads.legacy.clone().map(|data| (data, Vec::new())),
+            };
+            if payload.is_none() {
+                report.skipped.push(peer);
+            }
+
+            Some((peer, payload?))
+        })
+        .map(|(peer, (data, transactions))| {
+            let message = Message {
+                id: outbound_id.id.to_usize().unwrap(),
+                data,
+            };
+            let cap = cap.clone();
+            tokio::spawn(async move {
+                // Marked up front so that shedding the queued message can undo it.
+                cap.mark_transactions_known(peer, transactions.iter().copied());
+                let delivery = cap
+                    .deliver(
+                        peer,
+                        OutboundEvent::Message {
+                            capability_name: capability_name(),
+                            message,
+                        },
+                    )
+                    .await;
+                if delivery != Delivery::Accepted {
+                    cap.forget_transactions(peer, transactions);
+                }
+                (peer, delivery)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for task in tasks {
+        match task.await {
+            Ok((peer, Delivery::Accepted)) => report.sent.push(peer),
+            Ok((peer, Delivery::TimedOut)) => {
+                debug!("Outbound queue of peer {} is full, message dropped", peer);
+                report.timed_out.push(peer);
+            }
+            Ok((peer, Delivery::PeerGone)) => {
+                debug!("Peer {} is gone, message dropped", peer);
+                report.gone.push(peer);
+            }
+            Err(e) => {
+                warn!("Delivery task failed: {}", e);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
 impl SentryService {
+    /// Sends the message to every peer selected by `pred`.
+    ///
+    /// Returns the peers whose outbound queue accepted the message. The rest of `SendReport` has
+    /// no place in `SentPeers`, `SentryExt::SendMessage` reports it instead.
     async fn send_by_predicate<F, IT>(
         &self,
         request: Option<OutboundMessageData>,
@@ -43,85 +408,23 @@ impl SentryService {
         F: FnOnce(&CapabilityServerImpl) -> IT,
         IT: IntoIterator<Item = PeerIdHash>,
     {
-        let result = self.try_send_by_predicate(request, pred).await;
-        result.unwrap_or_else(|error| {
+        let report = match request {
+            Some(OutboundMessageData { id, data }) => {
+                let peers = (pred)(&*self.capability_server);
+                send_and_report(&self.capability_server, id, data, peers).await
+            }
+            None => Err(anyhow::anyhow!("empty request")),
+        };
+        let report = report.unwrap_or_else(|error| {
             warn!(
                 "SentryService send_by_predicate ignores a message: {:?}",
                 error
             );
-            SentPeers { peers: Vec::new() }
-        })
-    }
-
-    async fn try_send_by_predicate<F, IT>(
-        &self,
-        request: Option<OutboundMessageData>,
-        pred: F,
-    ) -> anyhow::Result<SentPeers>
-    where
-        F: FnOnce(&CapabilityServerImpl) -> IT,
-        IT: IntoIterator<Item = PeerIdHash>,
-    {
-        let request = request.ok_or_else(|| anyhow::anyhow!("empty request"))?;
-
-        let proto_id = ProtoMessageId::from_i32(request.id)
-            .ok_or_else(|| anyhow::anyhow!("unrecognized ProtoMessageId"))?;
-        let eth_id = EthMessageId::try_from(proto_id)?;
-
-        let message = Message {
-            id: eth_id.to_usize().unwrap(),
-            data: request.data,
-        };
-        let cap = self.capability_server.clone();
-        let mut join_handler = Vec::new();
-
-        (pred)(&*cap).into_iter().for_each(|peer| {
-            let message = message.clone();
-            let cap = cap.clone();
-            join_handler.push(tokio::spawn(async move {
-                let sender = cap.sender(peer).unwrap();
-                let outbound_message = OutboundEvent::Message {
-                    capability_name: capability_name(),
-                    message,
-                };
-                match sender.send(outbound_message).await {
-                    Ok(_) => Ok(peer),
-                    Err(error) => Err(anyhow::anyhow!(error)),
-                }
-            }));
+            SendReport::default()
         });
 
-        let mut peers = Vec::new();
-        for task in join_handler {
-            match task.await {
-                Ok(peer) => peers.push(if let Ok(peer) = peer {
-                    peer
-                } else {
-                    continue;
-                }),
-                _ => continue,
-            }
-        }
-
-        Ok(SentPeers { peers: Vec::new() })
-    }
-
-    async fn send_message(&self, message: Message, peer: PeerIdHash) -> anyhow::Result<PeerIdHash> {
-        let sender = self
-            .capability_server
-            .sender(peer)
-            .ok_or_else(|| anyhow::anyhow!("sender not found for peer"))?;
-
-        let outbound_message = OutboundEvent::Message {
-            capability_name: capability_name(),
-            message,
-        };
-
-        let result = sender.send(outbound_message).await;
-
-        match result {
-            Ok(_) => Ok(peer),
-            Err(error) => Err(anyhow::anyhow!(error)),
+        SentPeers {
+            peers: report.sent.into_iter().map(Into::into).collect(),
         }
     }
 }
@@ -130,18 +433,25 @@ impl SentryService {
 impl Sentry for SentryService {
     async fn penalize_peer(
         &self,
-        request: tonic::Request<ethereum_interfaces::sentry::PenalizePeerRequest>,
+        request: tonic::Request<PenalizePeerRequest>,
     ) -> Result<Response<()>, tonic::Status> {
-        let peer = request
-            .into_inner()
-            .peer_id
+        let PenalizePeerRequest { peer_id, penalty } = request.into_inner();
+        let peer = peer_id
             .ok_or_else(|| tonic::Status::invalid_argument("no peer id"))?
             .into();
-        if let Some(sender) = self.capability_server.sender(peer) {
-            let _ = sender
-                .send(OutboundEvent::Disconnect {
-                    reason: DisconnectReason::DisconnectRequested,
-                })
+        let change = match PenaltyKind::from_i32(penalty) {
+            Some(PenaltyKind::Kick) => ReputationChange::Kick,
+            None => return Err(tonic::Status::invalid_argument("unknown penalty kind")),
+        };
+
+        if self.capability_server.report_peer(peer, change) != Verdict::Keep {
+            self.capability_server
+                .deliver(
+                    peer,
+                    OutboundEvent::Disconnect {
+                        reason: DisconnectReason::DisconnectRequested,
+                    },
+                )
                 .await;
         }
 
@@ -165,15 +475,22 @@ impl Sentry for SentryService {
         _request: tonic::Request<PeersRequest>,
     ) -> Result<Response<Self::PeersStream>, tonic::Status> {
         let receiver = self.capability_server.peers_status_sender.subscribe();
+        let metrics = self.capability_server.metrics.clone();
         let stream = BroadcastStream::new(receiver)
             // map BroadcastStreamRecvError to tonic::Status
-            .map_err(|error| match error {
-                BroadcastStreamRecvError::Lagged(_) => tonic::Status::new(
-                    tonic::Code::ResourceExhausted,
-                    "The receiver lagged too far behind. Some events dropped.",
-                ),
+            .map_err(move |error| match error {
+                BroadcastStreamRecvError::Lagged(dropped) => {
+                    metrics.on_lag("peers", dropped);
+                    tonic::Status::new(
+                        tonic::Code::ResourceExhausted,
+                        "The receiver lagged too far behind. Some events dropped.",
+                    )
+                }
             });
-        Ok(Response::new(Box::pin(stream)))
+        Ok(Response::new(Box::pin(until_shutdown(
+            stream,
+            self.capability_server.shutdown.clone(),
+        ))))
     }
 
     async fn send_message_by_min_block(
@@ -266,7 +583,7 @@ impl Sentry for SentryService {
         let s = FullStatusData::try_from(request.into_inner())
             .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
 
-        self.capability_server.set_status(s);
+        self.capability_server.set_status(s).await;
 
         Ok(Response::new(SetStatusReply {}))
     }
@@ -275,7 +592,7 @@ impl Sentry for SentryService {
         &self,
         _request: tonic::Request<()>,
     ) -> Result<Response<HandShakeReply>, tonic::Status> {
-        let protocol_version = self.capability_server.protocol_version;
+        let protocol_version = self.capability_server.max_protocol_version();
         let reply = HandShakeReply {
             protocol: ethereum_interfaces::sentry::Protocol::from(protocol_version) as i32,
         };
@@ -296,21 +613,83 @@ impl Sentry for SentryService {
             .collect::<HashSet<i32>>();
 
         let receiver = self.capability_server.data_sender.subscribe();
-        let stream = tokio_stream::StreamExt::map(
-            tokio_stream::StreamExt::filter(
-                tokio_stream::StreamExt::filter_map(BroadcastStream::new(receiver), |res| res.ok()),
-                move |message: &InboundMessage| ids_set.is_empty() || ids_set.contains(&message.id),
-            ),
-            Ok,
-        );
+        let metrics = self.capability_server.metrics.clone();
+        let stream = BroadcastStream::new(receiver)
+            // Lagging ends the stream, so that the core resubscribes knowing it missed messages.
+            .map_err(move |error| match error {
+                BroadcastStreamRecvError::Lagged(dropped) => {
+                    metrics.on_lag("messages", dropped);
+                    warn!(
+                        "Messages subscriber lagged, {} inbound messages dropped",
+                        dropped
+                    );
+                    tonic::Status::new(
+                        tonic::Code::ResourceExhausted,
+                        "The receiver lagged too far behind. Some messages dropped.",
+                    )
+                }
+            })
+            .try_filter(move |message| {
+                futures::future::ready(ids_set.is_empty() || ids_set.contains(&message.id))
+            });
 
-        Ok(Response::new(Box::pin(stream)))
+        Ok(Response::new(Box::pin(until_shutdown(
+            stream,
+            self.capability_server.shutdown.clone(),
+        ))))
     }
 
     async fn node_info(
         &self,
         _: tonic::Request<()>,
     ) -> Result<Response<NodeInfoReply>, tonic::Status> {
-        Err(tonic::Status::unimplemented("todo"))
+        let node_info = &self.node_info;
+        let ip = node_info.advertised_ip();
+
+        let enr = node_info
+            .enr(ip)
+            .map_err(|e| tonic::Status::internal(e.to_string()))?;
+
+        let mut protocols = serde_json::Map::new();
+        if let Some(FullStatusData {
+            status,
+            fork_filter,
+        }) = &*self.capability_server.status_message.read()
+        {
+            let fork_id = fork_filter.current();
+            protocols.insert(
+                capability_name().to_string(),
+                json!({
+                    "versions": self
+                        .capability_server
+                        .protocol_versions
+                        .iter()
+                        .map(|&version| version as usize)
+                        .collect::<Vec<_>>(),
+                    "network": status.network_id,
+                    "difficulty": status.total_difficulty.to_string(),
+                    "genesis": format!("{:?}", status.fork_data.genesis),
+                    "head": format!("{:?}", status.best_hash),
+                    "forkId": {
+                        "hash": format!("0x{}", hex::encode(fork_id.hash.0)),
+                        "next": fork_id.next,
+                    },
+                }),
+            );
+        }
+
+        Ok(Response::new(NodeInfoReply {
+            id: hex::encode(peer_id_hash_from_peer_id(node_info.peer_id()).as_bytes()),
+            name: node_info.client_version.clone(),
+            enode: node_info.enode(ip),
+            enr,
+            ports: Some(NodeInfoPorts {
+                discovery: node_info.discovery_port.unwrap_or_default().into(),
+                listener: node_info.listen_addr.port().into(),
+            }),
+            listener_addr: node_info.listen_addr.to_string(),
+            protocols: serde_json::to_vec(&protocols)
+                .map_err(|e| tonic::Status::internal(e.to_string()))?,
+        }))
     }
 }
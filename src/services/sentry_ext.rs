@@ -0,0 +1,356 @@
+use crate::{
+    block_tracker::PeerHead,
+    eth::{capability_name, BlockHashNumber, EthMessageId, NewBlockAnnouncement, StatusMessage},
+    message_log::{self, LogRead, SequencedMessage},
+    proto::sentry_ext::{
+        messages_event::Event, sentry_ext_server::SentryExt, Balancing, Capability, ForkId,
+        InboundMessage, Lagged, ListPeersReply, ListPeersRequest, MessagesEvent, MessagesRequest,
+        PeerDirection, PeerInfo as ProtoPeerInfo, PeerStatus, PropagateBlockReply,
+        PropagateBlockRequest, SendMessageReply, SendMessageRequest,
+    },
+    requests::RequestStats,
+    services::send_and_report,
+    shutdown::until_shutdown,
+    CapabilityServerImpl, Delivery, PeerInfo,
+};
+use async_stream::stream;
+use async_trait::async_trait;
+use bytes::Bytes;
+use devp2p::{ConnectionDirection, Message, OutboundEvent, PeerIdHash};
+use ethereum_types::H256;
+use futures::{future::join_all, Stream};
+use num_traits::ToPrimitive;
+use secp256k1::rand::{seq::SliceRandom, thread_rng};
+use std::{collections::HashSet, pin::Pin, sync::Arc};
+use tonic::Response;
+
+/// Maximum number of messages taken from the log at once.
+const MESSAGES_BATCH: usize = 256;
+
+pub type MessagesEventStream =
+    Pin<Box<dyn Stream<Item = Result<MessagesEvent, tonic::Status>> + Send + Sync>>;
+
+pub struct SentryExtService {
+    capability_server: Arc<CapabilityServerImpl>,
+}
+
+impl SentryExtService {
+    pub fn new(capability_server: Arc<CapabilityServerImpl>) -> Self {
+        Self { capability_server }
+    }
+}
+
+impl From<StatusMessage> for PeerStatus {
+    fn from(status: StatusMessage) -> Self {
+        let mut total_difficulty = [0; 32];
+        status.total_difficulty.to_big_endian(&mut total_difficulty);
+
+        Self {
+            protocol_version: status.protocol_version as u32,
+            network_id: status.network_id,
+            total_difficulty: total_difficulty.to_vec(),
+            best_hash: status.best_hash.as_bytes().to_vec(),
+            genesis_hash: status.genesis_hash.as_bytes().to_vec(),
+            fork_id: Some(ForkId {
+                hash: status.fork_id.hash.0.to_vec(),
+                next: status.fork_id.next,
+            }),
+        }
+    }
+}
+
+fn inbound_message(message: &SequencedMessage, epoch: u64) -> InboundMessage {
+    InboundMessage {
+        sequence: message.sequence,
+        id: message.message.id,
+        data: message.message.data.clone(),
+        peer_id: message
+            .message
+            .peer_id
+            .clone()
+            .map(|peer| H256::from(peer).as_bytes().to_vec())
+            .unwrap_or_default(),
+        epoch,
+    }
+}
+
+fn proto_peer_info(
+    peer: PeerIdHash,
+    info: PeerInfo,
+    block_number: u64,
+    head: Option<PeerHead>,
+    request_stats: RequestStats,
+    outbound_queue_depth: usize,
+) -> ProtoPeerInfo {
+    let mut capabilities = info
+        .capabilities
+        .into_iter()
+        .map(|(name, version)| Capability {
+            name: name.to_string(),
+            version: version as u32,
+        })
+        .collect::<Vec<_>>();
+    capabilities.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let (head_hash, head_total_difficulty) = head
+        .map(|head| {
+            let mut total_difficulty = [0; 32];
+            head.total_difficulty.to_big_endian(&mut total_difficulty);
+            (head.hash.as_bytes().to_vec(), total_difficulty.to_vec())
+        })
+        .unwrap_or_default();
+
+    ProtoPeerInfo {
+        id_hash: peer.as_bytes().to_vec(),
+        id: info.id.as_bytes().to_vec(),
+        remote_addr: info
+            .connection
+            .remote_addr
+            .map(|addr| addr.to_string())
+            .unwrap_or_default(),
+        direction: match info.connection.direction {
+            ConnectionDirection::Inbound => PeerDirection::Inbound,
+            ConnectionDirection::Outbound => PeerDirection::Outbound,
+        } as i32,
+        client_version: info.connection.client_version,
+        capabilities,
+        status: info.status.map(From::from),
+        block_number,
+        connected_secs: info.connected_at.elapsed().as_secs(),
+        pending_requests: request_stats.pending as u64,
+        timed_out_requests: request_stats.timed_out,
+        average_response_millis: request_stats
+            .average_latency
+            .map_or(0, |latency| latency.as_millis() as u64),
+        head_hash,
+        head_total_difficulty,
+        outbound_queue_depth: outbound_queue_depth as u64,
+    }
+}
+
+impl SentryExtService {
+    /// Sends the message to the peers, returning those whose outbound queue accepted it.
+    async fn send_to_peers(
+        &self,
+        peers: &[PeerIdHash],
+        id: EthMessageId,
+        data: Bytes,
+    ) -> Vec<PeerIdHash> {
+        let deliveries = join_all(peers.iter().map(|&peer| {
+            let event = OutboundEvent::Message {
+                capability_name: capability_name(),
+                message: Message {
+                    id: id.to_usize().unwrap(),
+                    data: data.clone(),
+                },
+            };
+            async move { (peer, self.capability_server.deliver(peer, event).await) }
+        }))
+        .await;
+
+        deliveries
+            .into_iter()
+            .filter(|(_, delivery)| *delivery == Delivery::Accepted)
+            .map(|(peer, _)| peer)
+            .collect()
+    }
+}
+
+#[async_trait]
+impl SentryExt for SentryExtService {
+    async fn list_peers(
+        &self,
+        _: tonic::Request<ListPeersRequest>,
+    ) -> Result<Response<ListPeersReply>, tonic::Status> {
+        let peers = self
+            .capability_server
+            .all_peer_info()
+            .into_iter()
+            .map(|(peer, info)| {
+                let block_number = self
+                    .capability_server
+                    .peer_block_number(peer)
+                    .unwrap_or_default();
+                let head = self.capability_server.peer_head(peer);
+                let request_stats = self.capability_server.request_stats(peer);
+                let outbound_queue_depth = self.capability_server.outbound_queue_depth(peer);
+                proto_peer_info(
+                    peer,
+                    info,
+                    block_number,
+                    head,
+                    request_stats,
+                    outbound_queue_depth,
+                )
+            })
+            .collect();
+
+        Ok(Response::new(ListPeersReply { peers }))
+    }
+
+    type MessagesStream = MessagesEventStream;
+
+    async fn messages(
+        &self,
+        request: tonic::Request<MessagesRequest>,
+    ) -> Result<Response<Self::MessagesStream>, tonic::Status> {
+        let MessagesRequest {
+            ids,
+            resume_after,
+            group,
+            consumer,
+            balancing,
+            resume_epoch,
+        } = request.into_inner();
+        let ids = ids.into_iter().collect::<HashSet<i32>>();
+
+        let log = &self.capability_server.message_log;
+        let subscription = if group.is_empty() {
+            log.subscribe()
+        } else {
+            let balancing = match Balancing::from_i32(balancing) {
+                Some(Balancing::PeerAffinity) => message_log::Balancing::PeerAffinity,
+                Some(Balancing::RoundRobin) => message_log::Balancing::RoundRobin,
+                None => return Err(tonic::Status::invalid_argument("unknown balancing")),
+            };
+            let consumer = (!consumer.is_empty()).then(|| consumer.as_str());
+            log.join(&group, consumer, balancing, ids.clone())
+                .map_err(|e| tonic::Status::failed_precondition(e.to_string()))?
+        };
+        let epoch = subscription.log().epoch();
+        if resume_after != 0 && resume_epoch != epoch {
+            return Err(tonic::Status::failed_precondition(format!(
+                "cannot resume from epoch {}, the sentry restarted and is at epoch {}",
+                resume_epoch, epoch
+            )));
+        }
+        let latest = subscription.log().latest();
+        if resume_after > latest {
+            return Err(tonic::Status::out_of_range(format!(
+                "cannot resume after message {}, latest is {}",
+                resume_after, latest
+            )));
+        }
+        let mut position = if resume_after == 0 {
+            latest
+        } else {
+            resume_after
+        };
+
+        let metrics = self.capability_server.metrics.clone();
+        let stream = stream! {
+            let log = subscription.log();
+            loop {
+                let new_message = log.new_message();
+                let LogRead { dropped, messages } = log.read_after(position, MESSAGES_BATCH);
+                if dropped > 0 {
+                    metrics.on_lag("message_log", dropped);
+                    yield Ok(MessagesEvent {
+                        event: Some(Event::Lagged(Lagged { dropped })),
+                    });
+                }
+
+                if messages.is_empty() {
+                    new_message.await;
+                    continue;
+                }
+
+                for message in messages {
+                    position = message.sequence;
+                    if (ids.is_empty() || ids.contains(&message.message.id))
+                        && message.is_for(subscription.consumer())
+                    {
+                        yield Ok(MessagesEvent {
+                            event: Some(Event::Message(inbound_message(&message, epoch))),
+                        });
+                    }
+                }
+            }
+        };
+
+        Ok(Response::new(Box::pin(until_shutdown(
+            stream,
+            self.capability_server.shutdown.clone(),
+        ))))
+    }
+
+    async fn propagate_block(
+        &self,
+        request: tonic::Request<PropagateBlockRequest>,
+    ) -> Result<Response<PropagateBlockReply>, tonic::Status> {
+        let new_block = request.into_inner().new_block;
+        let block = NewBlockAnnouncement::decode(&new_block)
+            .map_err(|e| tonic::Status::invalid_argument(format!("invalid new block: {}", e)))?;
+
+        // Same split as geth: the full block to the square root of peers, the hash to the rest.
+        let mut peers = self.capability_server.claim_peers_without_block(block.hash);
+        peers.shuffle(&mut thread_rng());
+        let (block_peers, hash_peers) = peers.split_at((peers.len() as f64).sqrt() as usize);
+
+        let hashes = rlp::encode_list::<BlockHashNumber, _>(&[BlockHashNumber {
+            hash: block.hash,
+            number: block.number,
+        }])
+        .freeze();
+        let (block_peers, hash_peers) = futures::join!(
+            self.send_to_peers(block_peers, EthMessageId::NewBlock, new_block),
+            self.send_to_peers(hash_peers, EthMessageId::NewBlockHashes, hashes),
+        );
+
+        // Peers the announcement did not reach may be picked again next time.
+        let sent = block_peers
+            .iter()
+            .chain(&hash_peers)
+            .collect::<HashSet<_>>();
+        for &peer in peers.iter().filter(|peer| !sent.contains(peer)) {
+            self.capability_server.forget_blocks(peer, [block.hash]);
+        }
+
+        Ok(Response::new(PropagateBlockReply {
+            block_peers: block_peers
+                .into_iter()
+                .map(|peer| peer.as_bytes().to_vec())
+                .collect(),
+            hash_peers: hash_peers
+                .into_iter()
+                .map(|peer| peer.as_bytes().to_vec())
+                .collect(),
+        }))
+    }
+    async fn send_message(
+        &self,
+        request: tonic::Request<SendMessageRequest>,
+    ) -> Result<Response<SendMessageReply>, tonic::Status> {
+        let SendMessageRequest { id, data, peer_ids } = request.into_inner();
+        let peers = if peer_ids.is_empty() {
+            self.capability_server.all_peers().into_iter().collect()
+        } else {
+            peer_ids
+                .iter()
+                .map(|id| {
+                    if id.len() != PeerIdHash::len_bytes() {
+                        return Err(tonic::Status::invalid_argument("peer ID must be 32 bytes"));
+                    }
+                    Ok(PeerIdHash::from_slice(id))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        let report = send_and_report(&self.capability_server, id, data, peers)
+            .await
+            .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+
+        let to_bytes = |peers: Vec<PeerIdHash>| {
+            peers
+                .into_iter()
+                .map(|peer| peer.as_bytes().to_vec())
+                .collect()
+        };
+        Ok(Response::new(SendMessageReply {
+            sent: to_bytes(report.sent),
+            timed_out: to_bytes(report.timed_out),
+            gone: to_bytes(report.gone),
+            skipped: to_bytes(report.skipped),
+        }))
+    }
+}
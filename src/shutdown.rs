@@ -0,0 +1,95 @@
+use async_stream::stream;
+use futures::{Stream, StreamExt};
+use tokio::sync::watch;
+
+/// Fires once the sentry starts shutting down.
+#[derive(Clone, Debug)]
+pub struct ShutdownSignal {
+    receiver: watch::Receiver<bool>,
+}
+
+impl ShutdownSignal {
+    /// Signal and the sender that triggers it.
+    pub fn new() -> (watch::Sender<bool>, Self) {
+        let (sender, receiver) = watch::channel(false);
+        (sender, Self { receiver })
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolves once shutdown is triggered, or the sender is gone.
+    pub async fn triggered(mut self) {
+        while !*self.receiver.borrow() {
+            if self.receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Ends the stream with an `UNAVAILABLE` status once shutdown is triggered, so that clients see why it ended.
+pub fn until_shutdown<T, S>(
+    stream: S,
+    shutdown: ShutdownSignal,
+) -> impl Stream<Item = Result<T, tonic::Status>>
+where
+    S: Stream<Item = Result<T, tonic::Status>>,
+{
+    stream! {
+        let stream = stream.take_until(shutdown.triggered());
+        futures::pin_mut!(stream);
+        while let Some(item) = stream.next().await {
+            yield item;
+        }
+        if stream.is_stopped() {
+            yield Err(tonic::Status::unavailable("sentry is shutting down"));
+        }
+    }
+}
+
+/// Resolves on SIGINT, or SIGTERM on Unix.
+pub async fn termination_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stream_ends_on_shutdown() {
+        let (sender, shutdown) = ShutdownSignal::new();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Result<u32, tonic::Status>>();
+        let stream = until_shutdown(
+            tokio_stream::wrappers::UnboundedReceiverStream::new(rx),
+            shutdown.clone(),
+        );
+        futures::pin_mut!(stream);
+
+        tx.send(Ok(1)).unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), 1);
+
+        sender.send(true).unwrap();
+        assert!(shutdown.is_triggered());
+        assert_eq!(
+            stream.next().await.unwrap().unwrap_err().code(),
+            tonic::Code::Unavailable
+        );
+        assert!(stream.next().await.is_none());
+    }
+}
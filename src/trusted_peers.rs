@@ -0,0 +1,166 @@
+use crate::CapabilityServerImpl;
+use devp2p::{NodeRecord, PeerId, Swarm};
+use futures::future::join_all;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::time::{sleep, timeout};
+use tracing::*;
+
+const MIN_REDIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_REDIAL_BACKOFF: Duration = Duration::from_secs(300);
+/// How long a single dial of a trusted peer may take.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug)]
+struct Redial {
+    addr: SocketAddr,
+    backoff: Duration,
+    next_attempt: Instant,
+}
+
+impl Redial {
+    fn new(addr: SocketAddr, now: Instant) -> Self {
+        Self {
+            addr,
+            backoff: MIN_REDIAL_BACKOFF,
+            next_attempt: now,
+        }
+    }
+}
+
+/// Trusted peers and when to redial the ones that are disconnected.
+#[derive(Debug, Default)]
+pub struct TrustedPeers {
+    peers: HashMap<PeerId, Redial>,
+}
+
+impl TrustedPeers {
+    /// Replace the trusted peers, keeping the backoff of the ones that stay at the same address.
+    pub fn set(&mut self, peers: HashMap<PeerId, SocketAddr>, now: Instant) {
+        let mut old = std::mem::take(&mut self.peers);
+        self.peers = peers
+            .into_iter()
+            .map(|(id, addr)| {
+                let redial = match old.remove(&id) {
+                    Some(redial) if redial.addr == addr => redial,
+                    _ => Redial::new(addr, now),
+                };
+                (id, redial)
+            })
+            .collect();
+    }
+
+    pub fn ids(&self) -> HashSet<PeerId> {
+        self.peers.keys().copied().collect()
+    }
+
+    /// Peers to check on and redial if disconnected.
+    fn due(&self, now: Instant) -> Vec<NodeRecord> {
+        self.peers
+            .iter()
+            .filter(|(_, redial)| redial.next_attempt <= now)
+            .map(|(&id, redial)| NodeRecord {
+                id,
+                addr: redial.addr,
+            })
+            .collect()
+    }
+
+    /// Peer has stayed connected, so it gets redialed quickly next time it drops.
+    fn on_connected(&mut self, id: PeerId) {
+        if let Some(redial) = self.peers.get_mut(&id) {
+            redial.backoff = MIN_REDIAL_BACKOFF;
+        }
+    }
+
+    fn on_dialed(&mut self, id: PeerId, now: Instant) {
+        if let Some(redial) = self.peers.get_mut(&id) {
+            redial.next_attempt = now + redial.backoff;
+            redial.backoff = (redial.backoff * 2).min(MAX_REDIAL_BACKOFF);
+        }
+    }
+}
+
+/// Redial trusted peers while they are disconnected, backing off exponentially until they stay connected.
+/// Banned ones are left alone until unbanned.
+pub async fn keep_trusted_peers_connected(swarm: Arc<Swarm<CapabilityServerImpl>>) {
+    loop {
+        let mut due = swarm.trusted_peers.lock().due(Instant::now());
+        {
+            let node_filter = swarm.node_filter.lock();
+            due.retain(|record| {
+                !node_filter.is_banned(record.id) && !node_filter.is_ip_banned(record.addr.ip())
+            });
+        }
+        let (connected, disconnected): (Vec<_>, Vec<_>) = due
+            .into_iter()
+            .partition(|record| swarm.is_connected(record.id));
+
+        let dials = join_all(disconnected.into_iter().map(|record| {
+            debug!("Dialing trusted peer {} at {}", record.id, record.addr);
+            let dial = timeout(DIAL_TIMEOUT, swarm.add_peer(record));
+            async move {
+                match dial.await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => debug!("Failed to dial trusted peer {}: {}", record.id, e),
+                    Err(_) => debug!("Timed out dialing trusted peer {}", record.id),
+                }
+                record.id
+            }
+        }))
+        .await;
+
+        {
+            let now = Instant::now();
+            let mut trusted_peers = swarm.trusted_peers.lock();
+            for record in connected {
+                trusted_peers.on_connected(record.id);
+            }
+            for id in dials {
+                trusted_peers.on_dialed(id, now);
+            }
+        }
+
+        sleep(CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let now = Instant::now();
+        let id = PeerId::repeat_byte(1);
+        let addr = "127.0.0.1:30303".parse().unwrap();
+        let mut trusted_peers = TrustedPeers::default();
+        trusted_peers.set([(id, addr)].into_iter().collect(), now);
+        assert_eq!(trusted_peers.due(now).len(), 1);
+
+        trusted_peers.on_dialed(id, now);
+        assert!(trusted_peers.due(now).is_empty());
+        assert_eq!(trusted_peers.due(now + MIN_REDIAL_BACKOFF).len(), 1);
+
+        let mut at = now;
+        for _ in 0..20 {
+            trusted_peers.on_dialed(id, at);
+            at += MAX_REDIAL_BACKOFF;
+        }
+        assert_eq!(trusted_peers.peers[&id].backoff, MAX_REDIAL_BACKOFF);
+
+        // Reloading keeps the backoff, while a connection resets it.
+        trusted_peers.set([(id, addr)].into_iter().collect(), at);
+        assert_eq!(trusted_peers.peers[&id].backoff, MAX_REDIAL_BACKOFF);
+        trusted_peers.on_connected(id);
+        assert_eq!(trusted_peers.peers[&id].backoff, MIN_REDIAL_BACKOFF);
+
+        trusted_peers.set(HashMap::new(), at);
+        assert!(trusted_peers.ids().is_empty());
+    }
+}
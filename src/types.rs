@@ -1,6 +1,75 @@
 use ethereum_types::H256;
+use hashlink::LinkedHashSet;
 use plain_hasher::PlainHasher;
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::BuildHasherDefault,
+};
 
-pub type H256Map<T> = HashMap<H256, T, PlainHasher>;
-pub type H256Set = HashSet<H256, PlainHasher>;
+pub type H256BuildHasher = BuildHasherDefault<PlainHasher>;
+pub type H256Map<T> = HashMap<H256, T, H256BuildHasher>;
+pub type H256Set = HashSet<H256, H256BuildHasher>;
+
+/// Set of hashes that forgets the least recently inserted ones once full.
+#[derive(Clone, Debug)]
+pub struct H256LruSet {
+    hashes: LinkedHashSet<H256, H256BuildHasher>,
+    capacity: usize,
+}
+
+impl H256LruSet {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            hashes: LinkedHashSet::with_hasher(Default::default()),
+            capacity,
+        }
+    }
+
+    pub fn insert(&mut self, hash: H256) {
+        if !self.hashes.insert(hash) {
+            self.hashes.to_back(&hash);
+        }
+        while self.hashes.len() > self.capacity {
+            self.hashes.pop_front();
+        }
+    }
+
+    pub fn remove(&mut self, hash: &H256) {
+        self.hashes.remove(hash);
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru_set() {
+        let mut set = H256LruSet::new(2);
+        set.insert(H256::repeat_byte(1));
+        set.insert(H256::repeat_byte(2));
+        set.insert(H256::repeat_byte(1));
+        set.insert(H256::repeat_byte(3));
+
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&H256::repeat_byte(1)));
+        assert!(!set.contains(&H256::repeat_byte(2)));
+        assert!(set.contains(&H256::repeat_byte(3)));
+
+        set.remove(&H256::repeat_byte(3));
+        assert!(!set.contains(&H256::repeat_byte(3)));
+        assert_eq!(set.len(), 1);
+    }
+}
@@ -0,0 +1,256 @@
+use crate::eth::{
+    BlockHashNumber, EthMessageId, EthProtocolVersion, NewBlockAnnouncement,
+    PooledTransactionAnnouncement,
+};
+use ethereum_types::H256;
+use rlp::{DecoderError, Rlp};
+
+/// Most items in a block, receipt or state response, as geth serves no more. Requests may ask
+/// for more, as the responder is free to truncate the reply.
+const MAX_RESPONSE_ITEMS: usize = 1024;
+/// Most hashes in a pooled transaction announcement or request.
+const MAX_POOLED_TRANSACTION_HASHES: usize = 4096;
+/// Size cap of messages made of hashes and numbers.
+const MAX_SMALL_MESSAGE_SIZE: usize = 256 * 1024;
+/// Size cap of messages carrying transactions or block data.
+const MAX_LARGE_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+
+fn max_size(id: EthMessageId) -> usize {
+    match id {
+        EthMessageId::Transactions
+        | EthMessageId::BlockHeaders
+        | EthMessageId::BlockBodies
+        | EthMessageId::NewBlock
+        | EthMessageId::PooledTransactions
+        | EthMessageId::NodeData
+        | EthMessageId::Receipts => MAX_LARGE_MESSAGE_SIZE,
+        _ => MAX_SMALL_MESSAGE_SIZE,
+    }
+}
+
+fn list_of(
+    rlp: &Rlp,
+    max_items: usize,
+    mut check: impl FnMut(&Rlp) -> Result<(), DecoderError>,
+) -> Result<(), DecoderError> {
+    if !rlp.is_list() {
+        return Err(DecoderError::RlpExpectedToBeList);
+    }
+    if rlp.item_count()? > max_items {
+        return Err(DecoderError::Custom("too many items"));
+    }
+
+    rlp.iter().try_for_each(|item| check(&item))
+}
+
+fn hash(rlp: &Rlp) -> Result<(), DecoderError> {
+    rlp.as_val::<H256>().map(drop)
+}
+
+fn validate_payload(id: EthMessageId, rlp: &Rlp) -> Result<(), DecoderError> {
+    match id {
+        // Decoded and validated during the handshake.
+        EthMessageId::Status => Ok(()),
+        EthMessageId::NewBlockHashes => list_of(rlp, MAX_RESPONSE_ITEMS, |item| {
+            item.as_val::<BlockHashNumber>().map(drop)
+        }),
+        EthMessageId::Transactions | EthMessageId::PooledTransactions => {
+            list_of(rlp, usize::MAX, |tx| {
+                // Legacy transactions are lists, typed ones are strings starting with the type.
+                if tx.is_list() || (tx.is_data() && !tx.is_empty()) {
+                    Ok(())
+                } else {
+                    Err(DecoderError::Custom("malformed transaction"))
+                }
+            })
+        }
+        EthMessageId::GetBlockHeaders => {
+            if rlp.item_count()? != 4 {
+                return Err(DecoderError::RlpIncorrectListLen);
+            }
+            let origin = rlp.at(0)?;
+            if origin.is_data() && origin.size() == 32 {
+                hash(&origin)?;
+            } else {
+                origin.as_val::<u64>()?;
+            }
+            rlp.val_at::<u64>(1)?;
+            rlp.val_at::<u64>(2)?;
+            if rlp.val_at::<u64>(3)? > 1 {
+                return Err(DecoderError::Custom("reverse is not a boolean"));
+            }
+
+            Ok(())
+        }
+        EthMessageId::BlockHeaders => list_of(rlp, MAX_RESPONSE_ITEMS, |header| {
+            if header.item_count()? < 15 {
+                return Err(DecoderError::RlpIncorrectListLen);
+            }
+            Ok(())
+        }),
+        EthMessageId::GetBlockBodies | EthMessageId::GetNodeData | EthMessageId::GetReceipts => {
+            // Only bounded by the message size cap.
+            list_of(rlp, usize::MAX, hash)
+        }
+        EthMessageId::BlockBodies => list_of(rlp, MAX_RESPONSE_ITEMS, |body| {
+            // Transactions and ommers, followed by withdrawals since Shanghai.
+            if !(2..=3).contains(&body.item_count()?) {
+                return Err(DecoderError::RlpIncorrectListLen);
+            }
+            if body.iter().all(|item| item.is_list()) {
+                Ok(())
+            } else {
+                Err(DecoderError::RlpExpectedToBeList)
+            }
+        }),
+        EthMessageId::NewBlock => NewBlockAnnouncement::decode(rlp.as_raw()).map(drop),
+        EthMessageId::NewPooledTransactionHashes => Ok(()),
+        EthMessageId::GetPooledTransactions => list_of(rlp, MAX_POOLED_TRANSACTION_HASHES, hash),
+        EthMessageId::NodeData => list_of(rlp, MAX_RESPONSE_ITEMS, |item| {
+            if item.is_data() {
+                Ok(())
+            } else {
+                Err(DecoderError::RlpExpectedToBeData)
+            }
+        }),
+        EthMessageId::Receipts => list_of(rlp, MAX_RESPONSE_ITEMS, |receipts| {
+            if receipts.is_list() {
+                Ok(())
+            } else {
+                Err(DecoderError::RlpExpectedToBeList)
+            }
+        }),
+    }
+}
+
+/// Checks size and outer structure of an inbound message, without decoding transactions or block contents.
+pub fn validate_message(
+    id: EthMessageId,
+    version: EthProtocolVersion,
+    data: &[u8],
+) -> Result<(), DecoderError> {
+    if data.len() > max_size(id) {
+        return Err(DecoderError::Custom("message too large"));
+    }
+
+    if id == EthMessageId::NewPooledTransactionHashes {
+        let announcement = PooledTransactionAnnouncement::decode_for(data, version)?;
+        if announcement.hashes().len() > MAX_POOLED_TRANSACTION_HASHES {
+            return Err(DecoderError::Custom("too many items"));
+        }
+        return Ok(());
+    }
+
+    let rlp = Rlp::new(data);
+    if version.has_request_ids() && (id.response_id().is_some() || id.is_response()) {
+        if rlp.item_count()? != 2 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        rlp.val_at::<u64>(0)?;
+        validate_payload(id, &rlp.at(1)?)
+    } else {
+        validate_payload(id, &rlp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlp::RlpStream;
+
+    fn get_block_headers(amount: u64) -> RlpStream {
+        let mut s = RlpStream::new_list(4);
+        s.append(&H256::repeat_byte(1))
+            .append(&amount)
+            .append(&0_u64)
+            .append(&0_u64);
+        s
+    }
+
+    #[test]
+    fn request_envelope() {
+        let request = get_block_headers(192).out();
+        let mut wrapped = RlpStream::new_list(2);
+        wrapped.append(&7_u64).append_raw(&request, 1);
+        let wrapped = wrapped.out();
+
+        assert!(validate_message(
+            EthMessageId::GetBlockHeaders,
+            EthProtocolVersion::Eth65,
+            &request
+        )
+        .is_ok());
+        assert!(validate_message(
+            EthMessageId::GetBlockHeaders,
+            EthProtocolVersion::Eth66,
+            &wrapped
+        )
+        .is_ok());
+        assert!(validate_message(
+            EthMessageId::GetBlockHeaders,
+            EthProtocolVersion::Eth66,
+            &request
+        )
+        .is_err());
+        assert!(validate_message(
+            EthMessageId::GetBlockHeaders,
+            EthProtocolVersion::Eth65,
+            &wrapped
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn limits() {
+        let version = EthProtocolVersion::Eth65;
+        // Larger requests than we would serve get truncated replies rather than a disconnect.
+        assert!(validate_message(
+            EthMessageId::GetBlockHeaders,
+            version,
+            &get_block_headers(MAX_RESPONSE_ITEMS as u64 + 1).out()
+        )
+        .is_ok());
+
+        let hashes = vec![H256::repeat_byte(1); MAX_RESPONSE_ITEMS + 1];
+        assert!(validate_message(
+            EthMessageId::GetBlockBodies,
+            version,
+            &rlp::encode_list::<H256, _>(&hashes)
+        )
+        .is_ok());
+        let too_many_hashes = vec![H256::repeat_byte(1); MAX_SMALL_MESSAGE_SIZE / 32];
+        assert!(validate_message(
+            EthMessageId::GetBlockBodies,
+            version,
+            &rlp::encode_list::<H256, _>(&too_many_hashes)
+        )
+        .is_err());
+        let mut headers = RlpStream::new_list(MAX_RESPONSE_ITEMS + 1);
+        for _ in 0..=MAX_RESPONSE_ITEMS {
+            headers.append_list::<u64, _>(&[0; 15]);
+        }
+        assert!(validate_message(EthMessageId::BlockHeaders, version, &headers.out()).is_err());
+        assert!(validate_message(
+            EthMessageId::GetPooledTransactions,
+            version,
+            &rlp::encode_list::<H256, _>(&hashes)
+        )
+        .is_ok());
+
+        assert!(validate_message(
+            EthMessageId::GetBlockBodies,
+            version,
+            &rlp::encode_list::<u64, _>(&[1, 2])
+        )
+        .is_err());
+        assert!(
+            validate_message(EthMessageId::Transactions, version, &rlp::encode(&1_u64)).is_err()
+        );
+        assert!(validate_message(
+            EthMessageId::NewBlockHashes,
+            version,
+            &vec![0xc0; MAX_SMALL_MESSAGE_SIZE + 1]
+        )
+        .is_err());
+    }
+}